@@ -1,15 +1,14 @@
-use std::sync::mpsc::Sender;
-use std::sync::Arc;
-use std::sync::Mutex;
-
 use axum::extract::FromRef;
 
+use crate::cluster::Cluster;
+use crate::fallback::ParkedPolls;
 use crate::helper::ConnectionPool;
 use crate::helper::GroupInfoTable;
 use crate::helper::MessageSender;
 use crate::helper::SessionMap;
 use crate::helper::UserConnectionMap;
-use crate::message::ChatMessage;
+use crate::schedule::Scheduler;
+use crate::tunnel::{TunnelConfig, TunnelShutdown};
 
 #[derive(Clone)]
 pub struct AppState {
@@ -18,6 +17,11 @@ pub struct AppState {
     pub group_info_table: GroupInfoTable,
     pub user_connection_map: UserConnectionMap,
     pub message_sender: MessageSender,
+    pub scheduler: Scheduler,
+    pub tunnel_config: TunnelConfig,
+    pub cluster: Cluster,
+    pub parked_polls: ParkedPolls,
+    pub tunnel_shutdown: TunnelShutdown,
 }
 
 impl FromRef<AppState> for SessionMap {
@@ -44,7 +48,18 @@ impl FromRef<AppState> for UserConnectionMap {
 }
 impl FromRef<AppState> for MessageSender {
     fn from_ref(input: &AppState) -> Self {
-        let sender = input.message_sender.lock().unwrap().clone();
-        Arc::new(Mutex::new(sender))
+        input.message_sender.clone()
+    }
+}
+
+impl FromRef<AppState> for Scheduler {
+    fn from_ref(input: &AppState) -> Self {
+        input.scheduler.clone()
+    }
+}
+
+impl FromRef<AppState> for Cluster {
+    fn from_ref(input: &AppState) -> Self {
+        input.cluster.clone()
     }
 }
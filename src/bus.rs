@@ -0,0 +1,149 @@
+use std::{env, fmt, future::Future};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use uuid::Uuid;
+
+use crate::cluster::ClusterMessage;
+
+const IN_PROCESS_BACKLOG: usize = 1024;
+
+/// a cluster message and the node it comes from
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct Envelope {
+    pub node: Uuid,
+    pub message: ClusterMessage,
+}
+
+#[derive(Debug)]
+pub enum BusError {
+    Postgres(sqlx::Error),
+    Redis(redis::RedisError),
+    /// the bus is gone, nothing more will arrive
+    Closed,
+}
+
+impl fmt::Display for BusError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BusError::Postgres(e) => write!(f, "postgres: {}", e),
+            BusError::Redis(e) => write!(f, "redis: {}", e),
+            BusError::Closed => write!(f, "event bus closed"),
+        }
+    }
+}
+
+impl From<sqlx::Error> for BusError {
+    fn from(e: sqlx::Error) -> Self {
+        BusError::Postgres(e)
+    }
+}
+
+impl From<redis::RedisError> for BusError {
+    fn from(e: redis::RedisError) -> Self {
+        BusError::Redis(e)
+    }
+}
+
+/// carries cluster messages between the nodes of a deployment
+pub trait EventBus: Send + 'static {
+    type Subscriber: BusSubscriber;
+
+    /// sends to every subscribed node, this one included, in publishing order
+    fn publish(&mut self, envelope: &Envelope)
+        -> impl Future<Output = Result<(), BusError>> + Send;
+
+    /// starts receiving whatever is published from now on
+    fn subscribe(&mut self) -> impl Future<Output = Result<Self::Subscriber, BusError>> + Send;
+}
+
+pub trait BusSubscriber: Send + 'static {
+    /// the next message, `None` when some may have been lost in between
+    fn next(&mut self) -> impl Future<Output = Result<Option<Envelope>, BusError>> + Send;
+}
+
+/// which bus the node uses, from `EVENT_BUS`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BusConfig {
+    InProcess,
+    Postgres,
+    Redis(String),
+}
+
+impl BusConfig {
+    pub fn from_env() -> Self {
+        match env::var("EVENT_BUS").as_deref() {
+            Ok("postgres") => BusConfig::Postgres,
+            Ok("redis") => BusConfig::Redis(
+                env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string()),
+            ),
+            _ => BusConfig::InProcess,
+        }
+    }
+}
+
+/// connects the nodes running in one process, a single node only hears itself
+#[derive(Debug, Clone)]
+pub struct InProcessBus {
+    sender: broadcast::Sender<Envelope>,
+}
+
+impl Default for InProcessBus {
+    fn default() -> Self {
+        InProcessBus {
+            sender: broadcast::channel(IN_PROCESS_BACKLOG).0,
+        }
+    }
+}
+
+pub struct InProcessSubscriber {
+    receiver: broadcast::Receiver<Envelope>,
+}
+
+impl EventBus for InProcessBus {
+    type Subscriber = InProcessSubscriber;
+
+    async fn publish(&mut self, envelope: &Envelope) -> Result<(), BusError> {
+        // nobody subscribed yet is not an error
+        let _ = self.sender.send(envelope.clone());
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<InProcessSubscriber, BusError> {
+        Ok(InProcessSubscriber {
+            receiver: self.sender.subscribe(),
+        })
+    }
+}
+
+impl BusSubscriber for InProcessSubscriber {
+    async fn next(&mut self) -> Result<Option<Envelope>, BusError> {
+        match self.receiver.recv().await {
+            Ok(envelope) => Ok(Some(envelope)),
+            Err(RecvError::Lagged(_)) => Ok(None),
+            Err(RecvError::Closed) => Err(BusError::Closed),
+        }
+    }
+}
+
+#[tokio::test]
+async fn in_process_bus_reaches_every_subscriber() {
+    let mut bus = InProcessBus::default();
+    let mut first = bus.subscribe().await.unwrap();
+    let mut second = bus.clone().subscribe().await.unwrap();
+    for group_id in [1, 2] {
+        let envelope = Envelope {
+            node: Uuid::new_v4(),
+            message: ClusterMessage::GroupChanged { group_id },
+        };
+        bus.publish(&envelope).await.unwrap();
+    }
+    for subscriber in [&mut first, &mut second] {
+        for expected in [1, 2] {
+            match subscriber.next().await.unwrap().unwrap().message {
+                ClusterMessage::GroupChanged { group_id } => assert_eq!(group_id, expected),
+                other => panic!("unexpected {:?}", other),
+            }
+        }
+    }
+}
@@ -0,0 +1,133 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tracing::{debug, info};
+use uuid::Uuid;
+
+use crate::{
+    bus::{BusError, BusSubscriber, Envelope, EventBus},
+    event::{ServerEvent, Target},
+    helper::{GroupInfoTable, UserConnectionMap},
+};
+
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// what one node tells the others
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ClusterMessage {
+    /// an event the sending node pushed, with the sequence numbers it assigned
+    Event {
+        recipients: Vec<(u64, Target, Option<u64>)>,
+        event: ServerEvent,
+    },
+    GroupChanged {
+        group_id: i64,
+    },
+}
+
+/// handle for publishing to the other nodes through whichever bus `run_cluster` runs
+#[derive(Debug, Clone)]
+pub struct Cluster {
+    node: Uuid,
+    sender: UnboundedSender<ClusterMessage>,
+}
+
+impl Cluster {
+    /// the handle, and the receiver `run_cluster` publishes from
+    pub fn new() -> (Self, UnboundedReceiver<ClusterMessage>) {
+        let (sender, receiver) = tokio::sync::mpsc::unbounded_channel();
+        let cluster = Cluster {
+            node: Uuid::new_v4(),
+            sender,
+        };
+        (cluster, receiver)
+    }
+
+    pub fn node(&self) -> Uuid {
+        self.node
+    }
+
+    pub fn publish(&self, message: ClusterMessage) {
+        if let Err(e) = self.sender.send(message) {
+            debug!("{:?}", e);
+        }
+    }
+}
+
+/// publishes what this node sends and applies what the other nodes publish to
+/// this node's connections and caches
+pub async fn run_cluster<B: EventBus>(
+    mut bus: B,
+    receiver: UnboundedReceiver<ClusterMessage>,
+    node: Uuid,
+    user_connection_map: UserConnectionMap,
+    group_info_table: GroupInfoTable,
+) {
+    let subscriber = loop {
+        match bus.subscribe().await {
+            Ok(s) => break s,
+            Err(e) => debug!("failed to subscribe to the event bus: {}", e),
+        }
+        tokio::time::sleep(RETRY_DELAY).await;
+    };
+    info!("node {} joined the event bus", node);
+    let listen = async { listen(subscriber, node, &user_connection_map, &group_info_table).await };
+    tokio::join!(publish(bus, node, receiver), listen);
+}
+
+/// sends published messages to the other nodes in order
+async fn publish<B: EventBus>(
+    mut bus: B,
+    node: Uuid,
+    mut receiver: UnboundedReceiver<ClusterMessage>,
+) {
+    while let Some(message) = receiver.recv().await {
+        let envelope = Envelope { node, message };
+        if let Err(e) = bus.publish(&envelope).await {
+            debug!("failed to notify other nodes: {}", e);
+        }
+    }
+}
+
+async fn listen<S: BusSubscriber>(
+    mut subscriber: S,
+    node: Uuid,
+    user_connection_map: &UserConnectionMap,
+    group_info_table: &GroupInfoTable,
+) {
+    loop {
+        match subscriber.next().await {
+            Ok(Some(envelope)) if envelope.node == node => {}
+            Ok(Some(envelope)) => apply(user_connection_map, group_info_table, envelope.message),
+            Ok(None) => {
+                // invalidations sent in the meantime are lost
+                debug!("event bus may have dropped messages");
+                group_info_table.lock().unwrap().clear();
+            }
+            Err(BusError::Closed) => break,
+            Err(e) => {
+                debug!("event bus: {}", e);
+                tokio::time::sleep(RETRY_DELAY).await;
+            }
+        }
+    }
+}
+
+fn apply(
+    user_connection_map: &UserConnectionMap,
+    group_info_table: &GroupInfoTable,
+    message: ClusterMessage,
+) {
+    match message {
+        ClusterMessage::Event { recipients, event } => {
+            let mut map = user_connection_map.lock().unwrap();
+            for (user_id, target, seq) in recipients {
+                map.deliver_remote(user_id, target, seq, &event);
+            }
+        }
+        ClusterMessage::GroupChanged { group_id } => {
+            group_info_table.lock().unwrap().forget(group_id);
+        }
+    }
+}
@@ -0,0 +1,171 @@
+use std::io::Write;
+
+use axum::{extract::ws::Message, http::HeaderValue};
+use flate2::{write::DeflateEncoder, Compression};
+use serde::{de::DeserializeOwned, Serialize};
+
+/// `Sec-WebSocket-Protocol` values offered on `/tunnel`, in order of preference
+pub const TUNNEL_PROTOCOLS: [&str; 2] = ["adv.msgpack", "adv.json"];
+/// offered in front of the plain ones when compression is enabled
+pub const TUNNEL_PROTOCOLS_DEFLATE: [&str; 4] = [
+    "adv.msgpack+deflate",
+    "adv.json+deflate",
+    "adv.msgpack",
+    "adv.json",
+];
+
+/// how tunnel frames are written, chosen once per connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    /// text frames, also used when the client asks for no subprotocol
+    Json,
+    /// binary frames with field names kept, so enums look the same as in json.
+    /// times are `[year, ordinal, hour, minute, second, nanosecond]` arrays in both
+    MessagePack,
+}
+
+/// the encoding plus whether server frames are deflated. compression is part of
+/// the `+deflate` protocols rather than permessage-deflate: each server frame is
+/// its own raw deflate binary frame, client frames stay uncompressed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Codec {
+    pub encoding: Encoding,
+    pub deflate: bool,
+}
+
+#[derive(Debug)]
+pub enum DecodeError {
+    Json(serde_json::Error),
+    MessagePack(rmp_serde::decode::Error),
+    /// a text frame on a binary connection or the other way round
+    UnexpectedFrame,
+}
+
+impl std::fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DecodeError::Json(e) => write!(f, "invalid json frame: {}", e),
+            DecodeError::MessagePack(e) => write!(f, "invalid msgpack frame: {}", e),
+            DecodeError::UnexpectedFrame => write!(f, "frame type does not match the encoding"),
+        }
+    }
+}
+
+impl Codec {
+    pub fn from_protocol(protocol: Option<&HeaderValue>) -> Self {
+        let protocol = protocol.and_then(|p| p.to_str().ok()).unwrap_or_default();
+        let (name, deflate) = match protocol.strip_suffix("+deflate") {
+            Some(name) => (name, true),
+            None => (protocol, false),
+        };
+        let encoding = match name {
+            "adv.msgpack" => Encoding::MessagePack,
+            _ => Encoding::Json,
+        };
+        Codec { encoding, deflate }
+    }
+
+    pub fn encode<T: Serialize>(self, value: &T) -> Message {
+        let bytes = match self.encoding {
+            Encoding::Json if !self.deflate => {
+                return Message::Text(serde_json::to_string(value).unwrap())
+            }
+            Encoding::Json => serde_json::to_vec(value).unwrap(),
+            Encoding::MessagePack => rmp_serde::to_vec_named(value).unwrap(),
+        };
+        if !self.deflate {
+            return Message::Binary(bytes);
+        }
+        let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+        encoder.write_all(&bytes).unwrap();
+        Message::Binary(encoder.finish().unwrap())
+    }
+
+    /// `None` for frames that carry no data, like pings
+    pub fn decode<T: DeserializeOwned>(self, message: &Message) -> Option<Result<T, DecodeError>> {
+        let decoded = match (self.encoding, message) {
+            (Encoding::Json, Message::Text(t)) => {
+                serde_json::from_str(t).map_err(DecodeError::Json)
+            }
+            (Encoding::MessagePack, Message::Binary(b)) => {
+                rmp_serde::from_slice(b).map_err(DecodeError::MessagePack)
+            }
+            (_, Message::Text(_) | Message::Binary(_)) => Err(DecodeError::UnexpectedFrame),
+            _ => return None,
+        };
+        Some(decoded)
+    }
+}
+
+#[test]
+fn message_pack_keeps_the_json_shape() {
+    let codec = Codec::from_protocol(Some(&HeaderValue::from_static("adv.msgpack")));
+    assert_eq!(codec.encoding, Encoding::MessagePack);
+    let value = serde_json::json!({"Pong": {"nonce": 3}});
+    let encoded = codec.encode(&value);
+    let decoded: serde_json::Value = codec.decode(&encoded).unwrap().unwrap();
+    assert_eq!(decoded, value);
+    let json = Codec::from_protocol(None);
+    assert_eq!(json.encoding, Encoding::Json);
+    assert!(matches!(
+        json.decode::<serde_json::Value>(&encoded),
+        Some(Err(DecodeError::UnexpectedFrame))
+    ));
+}
+
+#[test]
+fn message_frames_look_the_same_in_both_encodings() {
+    use crate::{event::ServerEvent, tunnel::ServerFrame};
+
+    let event: ServerEvent = serde_json::from_value(serde_json::json!({"Message": {
+        "message_type": "Private",
+        "message_id": 5,
+        "user_id": 100001,
+        "group_id": null,
+        "content": {"Text": {"text": "hi"}},
+        "expires_at": [2024, 100, 12, 30, 0, 500],
+    }}))
+    .unwrap();
+    let frame = ServerFrame::Event {
+        seq: Some(7),
+        event,
+    };
+    let codec = Codec {
+        encoding: Encoding::MessagePack,
+        deflate: false,
+    };
+    let decoded: serde_json::Value = codec.decode(&codec.encode(&frame)).unwrap().unwrap();
+    assert_eq!(decoded, serde_json::to_value(&frame).unwrap());
+    assert_eq!(
+        decoded["Event"]["event"]["Message"]["expires_at"],
+        serde_json::json!([2024, 100, 12, 30, 0, 500])
+    );
+}
+
+#[test]
+fn deflated_frames_inflate_back() {
+    use flate2::read::DeflateDecoder;
+    use std::io::Read;
+
+    let codec = Codec::from_protocol(Some(&HeaderValue::from_static("adv.json+deflate")));
+    assert_eq!(
+        codec,
+        Codec {
+            encoding: Encoding::Json,
+            deflate: true
+        }
+    );
+    let value = serde_json::json!({"Pong": {"nonce": 3}});
+    let bytes = match codec.encode(&value) {
+        Message::Binary(b) => b,
+        m => panic!("unexpected frame {:?}", m),
+    };
+    let mut inflated = String::new();
+    DeflateDecoder::new(&bytes[..])
+        .read_to_string(&mut inflated)
+        .unwrap();
+    assert_eq!(
+        serde_json::from_str::<serde_json::Value>(&inflated).unwrap(),
+        value
+    );
+}
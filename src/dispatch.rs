@@ -0,0 +1,256 @@
+use std::{
+    collections::hash_map::DefaultHasher,
+    hash::{Hash, Hasher},
+    num::NonZeroUsize,
+};
+
+use lru::LruCache;
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinSet,
+};
+use tracing::debug;
+
+use crate::{
+    event::{push_event, push_event_to, push_event_to_users, ServerEvent, Target},
+    forward::ForwardEvent,
+    group_info::get_group_users,
+    helper::{ConnectionPool, GroupInfoTable, PushSender, UserConnectionMap},
+    message::{ChatMessage, Conversation, MessagePlain, MessageType},
+    message_content::MessageContent,
+    outbox::{mark_dispatched, OutboxEntry, Outgoing},
+    push::OfflineMessage,
+};
+
+/// capacity of the channel handlers send stored messages through
+pub const DISPATCH_QUEUE: usize = 4096;
+const WORKERS: usize = 8;
+const WORKER_QUEUE: usize = 256;
+/// message ids each worker remembers to skip repeated deliveries
+const RECENTLY_DELIVERED: usize = 4096;
+
+/// messages of one conversation always go to the same worker, so they arrive in order
+fn shard_of(message: &ChatMessage) -> usize {
+    let mut hasher = DefaultHasher::new();
+    Conversation::of(message).key().hash(&mut hasher);
+    (hasher.finish() % WORKERS as u64) as usize
+}
+
+/// pushes stored messages to everyone in their conversation. on shutdown it stops
+/// taking new messages and returns once everything already queued is delivered
+pub async fn run_dispatcher(
+    pool: ConnectionPool,
+    group_info_table: GroupInfoTable,
+    mut receiver: mpsc::Receiver<OutboxEntry>,
+    user_connection_map: UserConnectionMap,
+    push_sender: PushSender,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    let mut workers = JoinSet::new();
+    let mut shards = vec![];
+    for _ in 0..WORKERS {
+        let (sender, worker_receiver) = mpsc::channel(WORKER_QUEUE);
+        shards.push(sender);
+        workers.spawn(run_worker(
+            pool.clone(),
+            group_info_table.clone(),
+            worker_receiver,
+            user_connection_map.clone(),
+            push_sender.clone(),
+        ));
+    }
+    let mut closing = false;
+    loop {
+        let entry = tokio::select! {
+            entry = receiver.recv() => match entry {
+                Some(m) => m,
+                None => break,
+            },
+            _ = shutdown.changed(), if !closing => {
+                closing = true;
+                receiver.close();
+                continue;
+            }
+        };
+        let shard = shard_of(entry.outgoing.lead());
+        if let Err(e) = shards[shard].send(entry).await {
+            debug!("{:?}", e);
+        }
+    }
+    drop(shards);
+    while workers.join_next().await.is_some() {}
+    debug!("dispatcher drained");
+}
+
+async fn run_worker(
+    pool: ConnectionPool,
+    group_info_table: GroupInfoTable,
+    mut receiver: mpsc::Receiver<OutboxEntry>,
+    user_connection_map: UserConnectionMap,
+    push_sender: PushSender,
+) {
+    // a conversation always lands on the same worker, so repeats do too
+    let mut delivered = LruCache::new(NonZeroUsize::new(RECENTLY_DELIVERED).unwrap());
+    while let Some(OutboxEntry {
+        outbox_id,
+        outgoing,
+    }) = receiver.recv().await
+    {
+        // a forward is keyed by its first message, which is never sent alone
+        let lead = outgoing.lead();
+        let key = (
+            lead.message_type,
+            lead.message_id,
+            matches!(outgoing, Outgoing::Forwarded { .. }),
+        );
+        if delivered.put(key, ()).is_none() {
+            deliver(
+                &pool,
+                &group_info_table,
+                &user_connection_map,
+                &push_sender,
+                &outgoing,
+            )
+            .await;
+        } else {
+            debug!("message {:?} already delivered", key);
+        }
+        if let Err(e) = mark_dispatched(&pool, outbox_id).await {
+            debug!("failed to mark outbox entry {}: {:?}", outbox_id, e);
+        }
+    }
+}
+
+async fn deliver(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    user_connection_map: &UserConnectionMap,
+    push_sender: &PushSender,
+    outgoing: &Outgoing,
+) {
+    debug!("{:?}", outgoing);
+    let msg = outgoing.lead();
+    let event = match outgoing {
+        Outgoing::Message(msg) => ServerEvent::Message(MessagePlain::from(msg)),
+        Outgoing::Forwarded { forwarded } => ServerEvent::MessagesForwarded(ForwardEvent {
+            messages: forwarded.iter().map(MessagePlain::from).collect(),
+        }),
+    };
+    match msg.message_type {
+        MessageType::Private => {
+            push_event(user_connection_map, msg.receiver_id, &event);
+            notify_offline(user_connection_map, push_sender, [msg.receiver_id], msg);
+            // keeps the sender's other devices in sync
+            if msg.sender_id != msg.receiver_id {
+                push_event_to(user_connection_map, msg.sender_id, echo_target(msg), &event);
+            }
+        }
+        MessageType::Group => {
+            let group_user_ids =
+                match get_group_users(group_info_table, pool, msg.receiver_id as i64).await {
+                    Ok(g) => g,
+                    Err(e) => {
+                        debug!("{:?}", e);
+                        vec![]
+                    }
+                };
+            let recipients = group_user_ids.iter().map(|uid| {
+                let target = if *uid as u64 == msg.sender_id {
+                    echo_target(msg)
+                } else {
+                    Target::All
+                };
+                (*uid as u64, target)
+            });
+            push_event_to_users(user_connection_map, recipients, &event);
+            let user_ids = group_user_ids.into_iter().map(|uid| uid as u64);
+            notify_offline(user_connection_map, push_sender, user_ids, msg);
+        }
+    }
+}
+
+/// recipients without a connection on this node may get a push notification
+/// instead, the push worker checks the other nodes before sending it
+fn notify_offline(
+    user_connection_map: &UserConnectionMap,
+    push_sender: &PushSender,
+    user_ids: impl IntoIterator<Item = u64>,
+    msg: &ChatMessage,
+) {
+    if matches!(msg.content, MessageContent::System { .. }) {
+        return;
+    }
+    let offline: Vec<u64> = {
+        let map = user_connection_map.lock().unwrap();
+        user_ids
+            .into_iter()
+            .filter(|uid| *uid != msg.sender_id && !map.is_online(*uid))
+            .collect()
+    };
+    for user_id in offline {
+        // a full queue only costs a notification, delivery goes on
+        if let Err(e) = push_sender.try_send(OfflineMessage::new(user_id, msg)) {
+            debug!("{:?}", e);
+        }
+    }
+}
+
+fn echo_target(msg: &ChatMessage) -> Target {
+    match msg.origin {
+        Some(connection_id) => Target::Except(connection_id),
+        None => Target::All,
+    }
+}
+
+#[test]
+fn private_conversation_keeps_one_shard() {
+    let message = |sender_id, receiver_id| ChatMessage {
+        message_type: MessageType::Private,
+        message_id: None,
+        content: crate::message_content::MessageContent::Text {
+            text: "hi".to_string(),
+        },
+        sender_id,
+        receiver_id,
+        time: crate::helper::now_utc(),
+        expires_at: None,
+        origin: None,
+    };
+    assert_eq!(shard_of(&message(1, 2)), shard_of(&message(2, 1)));
+}
+
+#[test]
+fn group_members_but_the_sender_get_pushed() {
+    use std::sync::{Arc, Mutex};
+
+    use crate::{cluster::Cluster, event::Connections};
+
+    let (persist, _) = mpsc::channel(1);
+    let (cluster, _) = Cluster::new();
+    let user_connection_map: UserConnectionMap =
+        Arc::new(Mutex::new(Connections::new(persist, cluster, 0, 0)));
+    let (push_sender, mut pushed) = mpsc::channel(8);
+    let mut message = ChatMessage {
+        message_type: MessageType::Group,
+        message_id: Some(1),
+        content: MessageContent::Text {
+            text: "hi".to_string(),
+        },
+        sender_id: 1,
+        receiver_id: 7,
+        time: crate::helper::now_utc(),
+        expires_at: None,
+        origin: None,
+    };
+    notify_offline(&user_connection_map, &push_sender, [1, 2, 3], &message);
+    let mut user_ids = vec![];
+    while let Ok(offline) = pushed.try_recv() {
+        user_ids.push(offline.user_id);
+    }
+    assert_eq!(user_ids, vec![2, 3]);
+    message.content = MessageContent::System {
+        notice: crate::message_content::SystemNotice::MemberJoined { user_id: 3 },
+    };
+    notify_offline(&user_connection_map, &push_sender, [1, 2, 3], &message);
+    assert!(pushed.try_recv().is_err());
+}
@@ -0,0 +1,247 @@
+use std::{collections::HashMap, time::Duration};
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::PgConnection;
+use time::PrimitiveDateTime;
+use tracing::debug;
+
+use crate::{
+    event::{push_event, ServerEvent},
+    group_info::is_group_admin,
+    helper::{get_user_id, ConnectionPool, Session, SessionMap, UserConnectionMap},
+    message::{Conversation, MessageType},
+    user_info::user_exists,
+};
+
+const MIN_TTL_SECONDS: u32 = 5;
+const MAX_TTL_SECONDS: u32 = 7 * 24 * 60 * 60;
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+
+pub fn check_ttl(ttl_seconds: u32) -> bool {
+    (MIN_TTL_SECONDS..=MAX_TTL_SECONDS).contains(&ttl_seconds)
+}
+
+/// disappearing time set for the conversation, if any
+pub async fn conversation_ttl(
+    conn: &mut PgConnection,
+    conversation: Conversation,
+) -> Result<Option<u32>, sqlx::Error> {
+    let ttl = sqlx::query_as::<_, (i32,)>(
+        r#"
+        SELECT ttl_seconds
+        FROM adv_chat.conversation_ttl
+        WHERE conversation_key = $1
+        "#,
+    )
+    .bind(conversation.key())
+    .fetch_optional(conn)
+    .await?;
+    Ok(ttl.map(|t| t.0 as u32))
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ConversationTtlRequest {
+    session: Session,
+    message_type: MessageType,
+    /// the group id, or the other participant of a private chat
+    conversation_id: u64,
+    /// `None` turns disappearing messages off
+    ttl_seconds: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum ConversationTtlState {
+    Ok,
+    NotLogin,
+    InvalidTtl,
+    PermissionDenied,
+    OtherError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ConversationTtlResult {
+    state: ConversationTtlState,
+}
+
+pub async fn set_conversation_ttl(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(ttl_req): Json<ConversationTtlRequest>,
+) -> Json<ConversationTtlResult> {
+    let state = match get_user_id(session_map, ttl_req.session).await {
+        Some(user_id) => match update_conversation_ttl(&pool, user_id as i64, &ttl_req).await {
+            Ok(state) => state,
+            Err(e) => {
+                debug!("failed to set conversation ttl: {:?}", e);
+                ConversationTtlState::OtherError
+            }
+        },
+        None => ConversationTtlState::NotLogin,
+    };
+    ConversationTtlResult { state }.into()
+}
+
+async fn update_conversation_ttl(
+    pool: &ConnectionPool,
+    user_id: i64,
+    ttl_req: &ConversationTtlRequest,
+) -> Result<ConversationTtlState, sqlx::Error> {
+    if let Some(ttl) = ttl_req.ttl_seconds {
+        if !check_ttl(ttl) {
+            return Ok(ConversationTtlState::InvalidTtl);
+        }
+    }
+    let conversation_id = ttl_req.conversation_id as i64;
+    let (conversation, allowed) = match ttl_req.message_type {
+        MessageType::Private => (
+            Conversation::private(user_id, conversation_id),
+            user_exists(pool, conversation_id).await?,
+        ),
+        MessageType::Group => (
+            Conversation::Group(conversation_id),
+            is_group_admin(pool, conversation_id, user_id).await?,
+        ),
+    };
+    if !allowed {
+        return Ok(ConversationTtlState::PermissionDenied);
+    }
+    match ttl_req.ttl_seconds {
+        Some(ttl) => {
+            sqlx::query(
+                r#"
+                INSERT INTO adv_chat.conversation_ttl
+                (conversation_key, ttl_seconds, updated_by, updated_at)
+                VALUES($1, $2, $3, now() at time zone 'utc')
+                ON CONFLICT (conversation_key)
+                DO UPDATE SET ttl_seconds = $2, updated_by = $3, updated_at = now() at time zone 'utc'
+                "#,
+            )
+            .bind(conversation.key())
+            .bind(ttl as i32)
+            .bind(user_id)
+            .execute(pool)
+            .await?;
+        }
+        None => {
+            sqlx::query(
+                r#"
+                DELETE FROM adv_chat.conversation_ttl
+                WHERE conversation_key = $1
+                "#,
+            )
+            .bind(conversation.key())
+            .execute(pool)
+            .await?;
+        }
+    }
+    Ok(ConversationTtlState::Ok)
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ExpiredEvent {
+    message_type: MessageType,
+    message_ids: Vec<u64>,
+}
+
+/// deletes expired messages periodically and tells online participants to drop them
+pub async fn run_sweeper(pool: ConnectionPool, user_connection_map: UserConnectionMap) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_expired(&pool, &user_connection_map).await {
+            debug!("failed to sweep expired messages: {:?}", e);
+        }
+    }
+}
+
+async fn sweep_expired(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+) -> Result<(), sqlx::Error> {
+    // messages and their pins go together
+    let mut tx = pool.begin().await?;
+    let private = sqlx::query_as::<_, (i64, i64, i64)>(
+        r#"
+        DELETE FROM adv_chat.private_message
+        WHERE expires_at <= now() at time zone 'utc'
+        RETURNING message_id, message_from, message_to
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    let group = sqlx::query_as::<_, (i64, i64, PrimitiveDateTime)>(
+        r#"
+        DELETE FROM adv_chat.group_message
+        WHERE expires_at <= now() at time zone 'utc'
+        RETURNING group_message_id, group_id, created_at
+        "#,
+    )
+    .fetch_all(&mut *tx)
+    .await?;
+    if private.is_empty() && group.is_empty() {
+        return Ok(());
+    }
+    let private_ids: Vec<i64> = private.iter().map(|m| m.0).collect();
+    let group_message_ids: Vec<i64> = group.iter().map(|m| m.0).collect();
+    sqlx::query(
+        r#"
+        DELETE FROM adv_chat.pinned_message
+        WHERE (NOT is_group AND message_id = ANY($1))
+        OR (is_group AND message_id = ANY($2))
+        "#,
+    )
+    .bind(&private_ids)
+    .bind(&group_message_ids)
+    .execute(&mut *tx)
+    .await?;
+    // the members who could see each message: not banned, and in the group
+    // since before it was sent, like sync and search
+    let group_recipients = sqlx::query_as::<_, (i64, i64)>(
+        r#"
+        SELECT m.message_id, u.user_id
+        FROM UNNEST($1::bigint[], $2::bigint[], $3::timestamp[]) AS m(message_id, group_id, created_at)
+        JOIN adv_chat.group g ON g.group_id = m.group_id
+        CROSS JOIN LATERAL UNNEST(g.user_list) AS u(user_id)
+        LEFT JOIN adv_chat.group_member gm ON gm.group_id = m.group_id AND gm.user_id = u.user_id
+        WHERE NOT u.user_id = ANY(COALESCE(g.banned_list, '{}'))
+        AND COALESCE(gm.joined_at, '-infinity') <= m.created_at
+        "#,
+    )
+    .bind(&group_message_ids)
+    .bind(group.iter().map(|m| m.1).collect::<Vec<i64>>())
+    .bind(group.iter().map(|m| m.2).collect::<Vec<PrimitiveDateTime>>())
+    .fetch_all(&mut *tx)
+    .await?;
+    tx.commit().await?;
+
+    let mut private_expired: HashMap<u64, Vec<u64>> = HashMap::new();
+    for (message_id, from, to) in private {
+        for user_id in [from, to] {
+            private_expired
+                .entry(user_id as u64)
+                .or_default()
+                .push(message_id as u64);
+        }
+    }
+    let mut group_expired: HashMap<u64, Vec<u64>> = HashMap::new();
+    for (message_id, user_id) in group_recipients {
+        group_expired
+            .entry(user_id as u64)
+            .or_default()
+            .push(message_id as u64);
+    }
+    for (message_type, expired) in [
+        (MessageType::Private, private_expired),
+        (MessageType::Group, group_expired),
+    ] {
+        for (user_id, message_ids) in expired {
+            let event = ServerEvent::MessagesExpired(ExpiredEvent {
+                message_type,
+                message_ids,
+            });
+            push_event(user_connection_map, user_id, &event);
+        }
+    }
+    Ok(())
+}
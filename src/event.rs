@@ -0,0 +1,496 @@
+use std::{collections::HashMap, collections::VecDeque, num::NonZeroUsize};
+
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tokio::sync::mpsc::{error::TrySendError, Sender};
+use tracing::debug;
+
+use crate::{
+    cluster::{Cluster, ClusterMessage},
+    ephemeral::ExpiredEvent,
+    forward::ForwardEvent,
+    helper::UserConnectionMap,
+    message::MessagePlain,
+    pin::PinEvent,
+    presence::PresenceEvent,
+    replay::{LoggedEvent, SEQ_SLOTS},
+    tunnel::{Connection, ConnectionId, ReadEvent, ServerFrame},
+    typing::TypingEvent,
+};
+
+const LOGGED_USERS: usize = 16 * 1024;
+const EVENTS_PER_USER: usize = 128;
+
+/// everything pushed to a user through the tunnel
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum ServerEvent {
+    Message(MessagePlain),
+    Typing(TypingEvent),
+    Read(ReadEvent),
+    Presence(PresenceEvent),
+    MessagePinned(PinEvent),
+    MessageUnpinned(PinEvent),
+    MessagesForwarded(ForwardEvent),
+    MessagesExpired(ExpiredEvent),
+}
+
+impl ServerEvent {
+    /// durable events get a sequence number and are replayed on resume
+    pub fn is_durable(&self) -> bool {
+        !matches!(self, ServerEvent::Typing(_) | ServerEvent::Presence(_))
+    }
+
+    /// the event without the messages that expired by `now`, replays leave out
+    /// what the sweeper deleted or is about to
+    pub fn unexpired(self, now: PrimitiveDateTime) -> Option<Self> {
+        match self {
+            ServerEvent::Message(message) if message.expired(now) => None,
+            ServerEvent::MessagesForwarded(mut forwarded) => {
+                forwarded.messages.retain(|m| !m.expired(now));
+                if forwarded.messages.is_empty() {
+                    None
+                } else {
+                    Some(ServerEvent::MessagesForwarded(forwarded))
+                }
+            }
+            event => Some(event),
+        }
+    }
+}
+
+/// which of a user's connections an event goes to
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Target {
+    All,
+    /// usually the connection the change came from
+    Except(ConnectionId),
+}
+
+impl Target {
+    fn includes(self, connection_id: ConnectionId) -> bool {
+        match self {
+            Target::All => true,
+            Target::Except(id) => id != connection_id,
+        }
+    }
+}
+
+/// recent durable events of a user who connected lately. holds every event
+/// this node knows of after `floor`
+#[derive(Debug)]
+struct EventLog {
+    last: u64,
+    floor: u64,
+    recent: VecDeque<(u64, ServerEvent)>,
+}
+
+impl EventLog {
+    /// a log that can replay whatever comes after `last`
+    fn new(last: u64) -> Self {
+        EventLog {
+            last,
+            floor: last,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// stores an event, possibly numbered by another node. events from two
+    /// nodes at once may arrive out of order and are sorted in
+    fn adopt(&mut self, seq: u64, event: ServerEvent) {
+        if seq <= self.floor {
+            return;
+        }
+        self.last = self.last.max(seq);
+        let at = self.recent.partition_point(|e| e.0 < seq);
+        if self.recent.get(at).map(|e| e.0) == Some(seq) {
+            return;
+        }
+        self.recent.insert(at, (seq, event));
+        if self.recent.len() > EVENTS_PER_USER {
+            if let Some((dropped, _)) = self.recent.pop_front() {
+                self.floor = dropped;
+            }
+        }
+    }
+
+    /// `None` when events after `seq` have already left the buffer
+    fn events_after(&self, seq: u64) -> Option<Vec<(u64, ServerEvent)>> {
+        if seq > self.last || seq < self.floor {
+            return None;
+        }
+        Some(self.logged_after(seq))
+    }
+
+    fn logged_after(&self, seq: u64) -> Vec<(u64, ServerEvent)> {
+        self.recent.iter().filter(|e| e.0 > seq).cloned().collect()
+    }
+}
+
+/// open tunnels of online users, and the event logs used to resume them
+pub struct Connections {
+    users: HashMap<u64, HashMap<ConnectionId, Connection>>,
+    /// logs of users who connected lately, the others resume from the stored events
+    logs: LruCache<u64, EventLog>,
+    /// one counter for every user, so a user without a log still gets growing numbers
+    next_seq: u64,
+    /// numbers this node hands out are `slot` modulo `SEQ_SLOTS`
+    slot: u64,
+    persist: Sender<LoggedEvent>,
+    /// newest event of each user that didn't fit into the writer's queue, until
+    /// the writer takes a marker for it
+    unstored: HashMap<u64, u64>,
+    cluster: Cluster,
+}
+
+impl Connections {
+    /// numbers start after `last_seq`, the newest stored by any node
+    pub fn new(persist: Sender<LoggedEvent>, cluster: Cluster, slot: u64, last_seq: u64) -> Self {
+        // the clock covers events that were numbered but never stored
+        let micros = OffsetDateTime::now_utc().unix_timestamp_nanos() / 1000;
+        Connections {
+            users: HashMap::new(),
+            logs: LruCache::new(NonZeroUsize::new(LOGGED_USERS).unwrap()),
+            next_seq: (micros as u64).max(last_seq + 1),
+            slot,
+            persist,
+            unstored: HashMap::new(),
+            cluster,
+        }
+    }
+
+    /// returns whether the user just came online
+    pub fn register(
+        &mut self,
+        user_id: u64,
+        connection_id: ConnectionId,
+        connection: Connection,
+    ) -> bool {
+        self.log(user_id);
+        let connections = self.users.entry(user_id).or_default();
+        connections.insert(connection_id, connection);
+        connections.len() == 1
+    }
+
+    /// drops a closed connection, and the user once none are left,
+    /// returns whether the user went offline
+    pub fn remove(&mut self, user_id: u64, connection_id: ConnectionId) -> bool {
+        if let Some(connections) = self.users.get_mut(&user_id) {
+            connections.remove(&connection_id);
+            if connections.is_empty() {
+                self.users.remove(&user_id);
+                return true;
+            }
+        }
+        false
+    }
+
+    pub fn connections(&self) -> impl Iterator<Item = (ConnectionId, &Connection)> {
+        self.users
+            .values()
+            .flat_map(|connections| connections.iter().map(|(id, c)| (*id, c)))
+    }
+
+    /// whether the user has a connection on this node, see `presence::online_users`
+    /// for the whole cluster
+    pub fn is_online(&self, user_id: u64) -> bool {
+        self.users.contains_key(&user_id)
+    }
+
+    pub fn slot(&self) -> u64 {
+        self.slot
+    }
+
+    /// the user's log, started now when there is none
+    fn log(&mut self, user_id: u64) -> &mut EventLog {
+        let last = self.next_seq - 1;
+        self.logs.get_or_insert_mut(user_id, || EventLog::new(last))
+    }
+
+    /// the newest number handed out to anyone so far
+    pub fn newest_seq(&self) -> u64 {
+        self.next_seq - 1
+    }
+
+    /// sequence number of the newest event of the user
+    pub fn last_seq(&mut self, user_id: u64) -> u64 {
+        self.log(user_id).last
+    }
+
+    pub fn events_after(&mut self, user_id: u64, seq: u64) -> Option<Vec<(u64, ServerEvent)>> {
+        self.log(user_id).events_after(seq)
+    }
+
+    /// whatever the log holds after `seq`, for events loaded from the database
+    /// that may not include the newest
+    pub fn logged_after(&mut self, user_id: u64, seq: u64) -> Vec<(u64, ServerEvent)> {
+        self.log(user_id).logged_after(seq)
+    }
+
+    fn record(&mut self, user_id: u64, event: &ServerEvent) -> u64 {
+        let seq = next_in_slot(self.next_seq, self.slot);
+        self.next_seq = seq + 1;
+        if let Some(log) = self.logs.get_mut(&user_id) {
+            log.adopt(seq, event.clone());
+        }
+        self.store(LoggedEvent {
+            user_id,
+            seq,
+            event: Some(event.clone()),
+        });
+        seq
+    }
+
+    /// queues an event for the writer. one that doesn't fit leaves a marker
+    /// instead, so resuming from before it asks for a resync
+    fn store(&mut self, logged: LoggedEvent) {
+        while let Some((&user_id, &seq)) = self.unstored.iter().next() {
+            let marker = LoggedEvent {
+                user_id,
+                seq,
+                event: None,
+            };
+            if self.persist.try_send(marker).is_err() {
+                break;
+            }
+            self.unstored.remove(&user_id);
+        }
+        match self.persist.try_send(logged) {
+            Ok(()) => {}
+            Err(TrySendError::Full(logged)) => {
+                debug!("event {} of user {} not stored", logged.seq, logged.user_id);
+                let newest = self.unstored.entry(logged.user_id).or_default();
+                *newest = (*newest).max(logged.seq);
+            }
+            Err(TrySendError::Closed(_)) => {}
+        }
+    }
+
+    /// whether an event of the user after `seq` is neither stored nor marked yet
+    pub fn unstored_after(&self, user_id: u64, seq: u64) -> bool {
+        self.unstored
+            .get(&user_id)
+            .is_some_and(|newest| *newest > seq)
+    }
+
+    /// records the event, then queues it for the user's connections on this node
+    fn push(&mut self, user_id: u64, target: Target, event: &ServerEvent) -> Option<u64> {
+        let seq = if event.is_durable() {
+            Some(self.record(user_id, event))
+        } else {
+            None
+        };
+        self.deliver(user_id, target, seq, event);
+        seq
+    }
+
+    fn deliver(&self, user_id: u64, target: Target, seq: Option<u64>, event: &ServerEvent) {
+        if let Some(connections) = self.users.get(&user_id) {
+            for (connection_id, connection) in connections {
+                if !target.includes(*connection_id) {
+                    continue;
+                }
+                let frame = ServerFrame::Event {
+                    seq,
+                    event: event.clone(),
+                };
+                if !connection.sender.push(frame) {
+                    debug!("outbound queue of connection {} overflowed", connection_id);
+                }
+            }
+        }
+    }
+
+    /// an event another node pushed and already stored
+    pub fn deliver_remote(
+        &mut self,
+        user_id: u64,
+        target: Target,
+        seq: Option<u64>,
+        event: &ServerEvent,
+    ) {
+        if let Some(seq) = seq {
+            self.next_seq = self.next_seq.max(seq + 1);
+            if let Some(log) = self.logs.get_mut(&user_id) {
+                log.adopt(seq, event.clone());
+            }
+        }
+        self.deliver(user_id, target, seq, event);
+    }
+}
+
+/// the first number from `next` on that belongs to `slot`
+fn next_in_slot(next: u64, slot: u64) -> u64 {
+    let seq = next - next % SEQ_SLOTS + slot;
+    if seq < next {
+        seq + SEQ_SLOTS
+    } else {
+        seq
+    }
+}
+
+pub fn push_event(user_connection_map: &UserConnectionMap, user_id: u64, event: &ServerEvent) {
+    push_event_to(user_connection_map, user_id, Target::All, event);
+}
+
+pub fn push_event_to(
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+    target: Target,
+    event: &ServerEvent,
+) {
+    push_event_to_users(user_connection_map, [(user_id, target)], event);
+}
+
+/// pushes one event to many users, other nodes are told about it once
+pub fn push_event_to_users(
+    user_connection_map: &UserConnectionMap,
+    recipients: impl IntoIterator<Item = (u64, Target)>,
+    event: &ServerEvent,
+) {
+    let mut map = user_connection_map.lock().unwrap();
+    let recipients: Vec<_> = recipients
+        .into_iter()
+        .map(|(user_id, target)| (user_id, target, map.push(user_id, target, event)))
+        .collect();
+    if !recipients.is_empty() {
+        map.cluster.publish(ClusterMessage::Event {
+            recipients,
+            event: event.clone(),
+        });
+    }
+}
+
+#[cfg(test)]
+fn test_event() -> ServerEvent {
+    serde_json::from_str(r#"{"MessagesExpired":{"message_type":"Private","message_ids":[1]}}"#)
+        .unwrap()
+}
+
+#[test]
+fn target_includes() {
+    assert!(Target::All.includes(1));
+    assert!(!Target::Except(1).includes(1));
+    assert!(Target::Except(1).includes(2));
+}
+
+#[test]
+fn event_log_replays_only_what_it_still_holds() {
+    let event = test_event();
+    let mut log = EventLog::new(100);
+    log.adopt(101, event.clone());
+    assert_eq!(log.events_after(101).unwrap().len(), 0);
+    assert_eq!(log.events_after(100).unwrap().len(), 1);
+    // older than anything this log has seen
+    assert!(log.events_after(99).is_none());
+    for seq in 0..EVENTS_PER_USER as u64 {
+        log.adopt(102 + seq, event.clone());
+    }
+    // 101 has been dropped from the buffer
+    assert!(log.events_after(100).is_none());
+    assert_eq!(log.events_after(101).unwrap().len(), EVENTS_PER_USER);
+}
+
+#[test]
+fn event_log_adopts_numbers_from_other_nodes() {
+    let event = test_event();
+    let mut log = EventLog::new(100);
+    log.adopt(101, event.clone());
+    log.adopt(111, event.clone());
+    // a late event from a node that was behind
+    log.adopt(106, event.clone());
+    log.adopt(106, event.clone());
+    let seqs: Vec<u64> = log.events_after(100).unwrap().iter().map(|e| e.0).collect();
+    assert_eq!(seqs, vec![101, 106, 111]);
+    assert_eq!(log.events_after(106).unwrap().len(), 1);
+    assert_eq!(log.last, 111);
+}
+
+#[test]
+fn only_connected_users_keep_an_event_log() {
+    let event = test_event();
+    let (persist, mut stored) = tokio::sync::mpsc::channel(1);
+    let (cluster, _) = Cluster::new();
+    let mut map = Connections::new(persist, cluster, 3, 0);
+    let first = map.push(1, Target::All, &event).unwrap();
+    assert!(map.logs.is_empty());
+    assert_eq!(stored.try_recv().unwrap().seq, first);
+    let second = map.push(2, Target::All, &event).unwrap();
+    // the queue is full, the event is still numbered and delivered
+    assert!(map.push(2, Target::All, &event).unwrap() > second);
+    assert!(map.logs.is_empty());
+}
+
+#[test]
+fn nodes_never_hand_out_the_same_number() {
+    let event = test_event();
+    let mut nodes: Vec<Connections> = (0..2)
+        .map(|slot| {
+            let (persist, _) = tokio::sync::mpsc::channel(1);
+            let (cluster, _) = Cluster::new();
+            Connections::new(persist, cluster, slot, 0)
+        })
+        .collect();
+    nodes[1].next_seq = nodes[0].next_seq;
+    let a = nodes[0].push(1, Target::All, &event).unwrap();
+    let b = nodes[1].push(1, Target::All, &event).unwrap();
+    assert_ne!(a, b);
+    assert_eq!(a % SEQ_SLOTS, 0);
+    assert_eq!(b % SEQ_SLOTS, 1);
+    // an event from the other node moves the counter past it
+    nodes[0].deliver_remote(1, Target::All, Some(b), &event);
+    assert!(nodes[0].push(1, Target::All, &event).unwrap() > b);
+}
+
+#[test]
+fn events_that_dont_fit_the_queue_leave_a_marker() {
+    let event = test_event();
+    let (persist, mut stored) = tokio::sync::mpsc::channel(1);
+    let (cluster, _) = Cluster::new();
+    let mut map = Connections::new(persist, cluster, 0, 0);
+    let first = map.push(1, Target::All, &event).unwrap();
+    let lost = map.push(1, Target::All, &event).unwrap();
+    assert!(map.unstored_after(1, first));
+    assert!(!map.unstored_after(1, lost));
+    assert!(stored.try_recv().unwrap().event.is_some());
+    // the marker goes out before the next event, which doesn't fit either
+    let next = map.push(1, Target::All, &event).unwrap();
+    let marker = stored.try_recv().unwrap();
+    assert_eq!((marker.seq, marker.event.is_none()), (lost, true));
+    assert!(map.unstored_after(1, lost));
+    assert!(!map.unstored_after(1, next));
+}
+
+#[test]
+fn replays_leave_out_expired_messages() {
+    let now = crate::helper::now_utc();
+    let message = |expires_at: Option<PrimitiveDateTime>| {
+        MessagePlain::from(&crate::message::ChatMessage {
+            message_type: crate::message::MessageType::Private,
+            message_id: Some(1),
+            content: crate::message_content::MessageContent::Text {
+                text: "hi".to_string(),
+            },
+            sender_id: 1,
+            receiver_id: 2,
+            time: now,
+            expires_at,
+            origin: None,
+        })
+    };
+    let past = Some(now - time::Duration::seconds(1));
+    let future = Some(now + time::Duration::seconds(60));
+    assert!(ServerEvent::Message(message(past)).unexpired(now).is_none());
+    assert!(ServerEvent::Message(message(future))
+        .unexpired(now)
+        .is_some());
+    assert!(ServerEvent::Message(message(None)).unexpired(now).is_some());
+    let forwarded = ServerEvent::MessagesForwarded(ForwardEvent {
+        messages: vec![message(past), message(None)],
+    });
+    match forwarded.unexpired(now) {
+        Some(ServerEvent::MessagesForwarded(f)) => assert_eq!(f.messages.len(), 1),
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(test_event().unexpired(now).is_some());
+}
@@ -0,0 +1,336 @@
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::Infallible,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    extract::{Query, State},
+    http::HeaderMap,
+    response::{
+        sse::{Event as SseEvent, KeepAlive},
+        IntoResponse, Response, Sse,
+    },
+    Json,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::{mpsc, watch};
+use tracing::debug;
+use uuid::Uuid;
+
+use crate::{
+    app_state::AppState,
+    helper::{ConnectionPool, Session, UserConnectionMap},
+    outbound::OutboundQueue,
+    presence::presence_changed,
+    replay::resume,
+    tunnel::{next_connection_id, Connection, ConnectionId, ServerFrame},
+};
+
+/// how long a poll waits for something to happen
+const POLL_TIMEOUT: Duration = Duration::from_secs(25);
+/// how long a poll's connection stays registered for the next poll to pick up
+const POLL_GRACE: Duration = Duration::from_secs(30);
+
+/// removes the connection when the stream or poll is done with it
+struct Attached {
+    pool: ConnectionPool,
+    user_connection_map: UserConnectionMap,
+    user_id: u64,
+    connection_id: ConnectionId,
+    queue: Arc<OutboundQueue>,
+}
+
+/// a poll's connection waiting for the next poll, still collecting events
+pub struct ParkedPoll {
+    attached: Attached,
+    /// what the last poll answered, the next one continues from there
+    last_seq: u64,
+}
+
+pub type ParkedPolls = Arc<Mutex<HashMap<Session, ParkedPoll>>>;
+
+impl Drop for Attached {
+    fn drop(&mut self) {
+        let went_offline = self
+            .user_connection_map
+            .lock()
+            .unwrap()
+            .remove(self.user_id, self.connection_id);
+        if went_offline {
+            let pool = self.pool.clone();
+            let user_connection_map = self.user_connection_map.clone();
+            let user_id = self.user_id;
+            tokio::spawn(async move {
+                presence_changed(&pool, &user_connection_map, user_id).await;
+            });
+        }
+    }
+}
+
+/// registers a connection the way a tunnel does, replaying what was missed since `last_seq`
+async fn attach(
+    state: &AppState,
+    session: Session,
+    device: &str,
+    last_seq: Option<u64>,
+) -> Option<Attached> {
+    let user_id = state.sesson_map.user_id(session).await?;
+    let config = state.tunnel_config;
+    let queue = OutboundQueue::new(config.queue_capacity, config.queue_policy);
+    let connection_id = next_connection_id();
+    let connection = Connection {
+        sender: queue.clone(),
+        session,
+        device: Some(device.to_string()),
+    };
+    let came_online = resume(
+        &state.db_pool,
+        &state.user_connection_map,
+        user_id,
+        connection_id,
+        connection,
+        last_seq,
+    )
+    .await;
+    if came_online {
+        presence_changed(&state.db_pool, &state.user_connection_map, user_id).await;
+    }
+    Some(Attached {
+        pool: state.db_pool.clone(),
+        user_connection_map: state.user_connection_map.clone(),
+        user_id,
+        connection_id,
+        queue,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EventStreamParams {
+    last_seq: Option<u64>,
+}
+
+struct SseStream {
+    attached: Attached,
+    closing: watch::Receiver<bool>,
+    /// what was left to send when the server started closing
+    draining: Option<VecDeque<ServerFrame>>,
+    /// `TunnelCloser::close` waits for it like for a tunnel
+    _open: mpsc::Sender<()>,
+}
+
+impl SseStream {
+    /// `None` ends the stream, once the queue overflowed or what was queued at
+    /// shutdown is written
+    async fn next_frame(&mut self) -> Option<ServerFrame> {
+        if let Some(rest) = &mut self.draining {
+            return rest.pop_front();
+        }
+        tokio::select! {
+            frame = self.attached.queue.recv() => frame,
+            _ = self.closing.changed() => {
+                let mut rest: VecDeque<ServerFrame> = self.attached.queue.drain().into();
+                let frame = rest.pop_front();
+                self.draining = Some(rest);
+                frame
+            }
+        }
+    }
+}
+
+/// the push channel as server-sent events, each one a json `ServerFrame`.
+/// the session goes in `x-session-id`, query strings end up in access logs
+pub async fn event_stream(
+    State(state): State<AppState>,
+    Query(params): Query<EventStreamParams>,
+    headers: HeaderMap,
+) -> Response {
+    // browsers resend the last event id by themselves on reconnect
+    let last_seq = params.last_seq.or_else(|| {
+        headers
+            .get("last-event-id")
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse().ok())
+    });
+    let session = headers
+        .get("x-session-id")
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<Uuid>().ok())
+        .map(|session_id| Session { session_id });
+    let attached = match session {
+        Some(session) => attach(&state, session, "sse", last_seq).await,
+        None => None,
+    };
+    let attached = match attached {
+        Some(a) => a,
+        None => return axum::http::StatusCode::UNAUTHORIZED.into_response(),
+    };
+    let open = match state.tunnel_shutdown.hold_open() {
+        Some(open) => open,
+        None => return axum::http::StatusCode::SERVICE_UNAVAILABLE.into_response(),
+    };
+    let stream = SseStream {
+        attached,
+        closing: state.tunnel_shutdown.closing(),
+        draining: None,
+        _open: open,
+    };
+    let stream = futures::stream::unfold(stream, |mut stream| async move {
+        // ends the stream when the queue overflowed, the client reconnects with its last id
+        let frame = stream.next_frame().await?;
+        let mut event = match SseEvent::default().json_data(&frame) {
+            Ok(e) => e,
+            Err(e) => {
+                debug!("{:?}", e);
+                return None;
+            }
+        };
+        if let ServerFrame::Event { seq: Some(seq), .. } = &frame {
+            event = event.id(seq.to_string());
+        }
+        Some((Ok::<_, Infallible>(event), stream))
+    });
+    Sse::new(stream)
+        .keep_alive(KeepAlive::default())
+        .into_response()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PollRequest {
+    session: Session,
+    last_seq: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub enum PollState {
+    Ok,
+    NotLogin,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PollResult {
+    state: PollState,
+    /// ends with a `Welcome` carrying the `last_seq` for the next poll
+    frames: Vec<ServerFrame>,
+}
+
+/// returns missed events right away, otherwise waits a while for new ones.
+/// between polls the connection stays registered for `POLL_GRACE`, so the user
+/// stays online and events without a `seq` wait for the next poll
+pub async fn poll_events(
+    State(state): State<AppState>,
+    Json(poll_req): Json<PollRequest>,
+) -> Json<PollResult> {
+    let parked = state.parked_polls.lock().unwrap().remove(&poll_req.session);
+    let (attached, mut welcome) = match parked {
+        // the last answer arrived, what the connection collected since follows it
+        Some(parked) if poll_req.last_seq == Some(parked.last_seq) => {
+            let welcome = ServerFrame::Welcome {
+                connection_id: parked.attached.connection_id,
+                last_seq: parked.last_seq,
+            };
+            (Some(parked.attached), Some(welcome))
+        }
+        // otherwise resume from what the client says it has
+        _ => {
+            let attached = attach(&state, poll_req.session, "poll", poll_req.last_seq).await;
+            (attached, None)
+        }
+    };
+    let attached = match attached {
+        Some(a) => a,
+        None => {
+            return PollResult {
+                state: PollState::NotLogin,
+                frames: vec![],
+            }
+            .into();
+        }
+    };
+    let mut frames = vec![];
+    let mut waiting = welcome.is_some();
+    // an overflowed queue is closed, the next poll resumes from the stored events
+    let mut closed = false;
+    loop {
+        let frame = if waiting {
+            // a short grace period picks up events published right after the first one
+            let limit = if frames.is_empty() {
+                POLL_TIMEOUT
+            } else {
+                Duration::from_millis(50)
+            };
+            match tokio::time::timeout(limit, attached.queue.recv()).await {
+                Ok(Some(frame)) => frame,
+                Ok(None) => {
+                    closed = true;
+                    break;
+                }
+                Err(_) => break,
+            }
+        } else {
+            match attached.queue.recv().await {
+                Some(frame) => frame,
+                None => {
+                    closed = true;
+                    break;
+                }
+            }
+        };
+        match frame {
+            ServerFrame::Welcome { .. } => {
+                welcome = Some(frame);
+                waiting = true;
+            }
+            frame => frames.push(frame),
+        }
+    }
+    // the resume point has to cover what was picked up while waiting
+    let last_seq = frames
+        .iter()
+        .filter_map(|f| match f {
+            ServerFrame::Event { seq, .. } => *seq,
+            _ => None,
+        })
+        .max();
+    if let Some(ServerFrame::Welcome {
+        connection_id,
+        last_seq: welcome_seq,
+    }) = welcome
+    {
+        let last_seq = last_seq.map_or(welcome_seq, |s| s.max(welcome_seq));
+        frames.push(ServerFrame::Welcome {
+            connection_id,
+            last_seq,
+        });
+        if !closed {
+            park(&state.parked_polls, poll_req.session, attached, last_seq);
+        }
+    }
+    PollResult {
+        state: PollState::Ok,
+        frames,
+    }
+    .into()
+}
+
+/// keeps the connection for the next poll, dropping it once `POLL_GRACE` passes
+fn park(parked_polls: &ParkedPolls, session: Session, attached: Attached, last_seq: u64) {
+    let connection_id = attached.connection_id;
+    // a concurrent poll of the same session may have parked one already
+    let replaced = parked_polls
+        .lock()
+        .unwrap()
+        .insert(session, ParkedPoll { attached, last_seq });
+    drop(replaced);
+    let parked_polls = parked_polls.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(POLL_GRACE).await;
+        let mut parked = parked_polls.lock().unwrap();
+        if parked.get(&session).map(|p| p.attached.connection_id) == Some(connection_id) {
+            let expired = parked.remove(&session);
+            drop(parked);
+            drop(expired);
+        }
+    });
+}
@@ -0,0 +1,221 @@
+use std::collections::HashMap;
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::{
+    group_info::get_group_users,
+    helper::{
+        get_user_id, now_utc, ConnectionPool, GroupInfoTable, MessageSender, Session, SessionMap,
+    },
+    message::{check_receiver, ChatMessage, ChatMessageStored, MessagePlain, MessageType},
+    message_content::MessageContent,
+    outbox::record_and_dispatch_forwarded,
+};
+
+const MAX_FORWARD_MESSAGES: usize = 50;
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardSource {
+    message_type: MessageType,
+    message_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ForwardMessagesRequest {
+    session: Session,
+    messages: Vec<ForwardSource>,
+    target_type: MessageType,
+    target_id: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub enum ForwardMessagesState {
+    Ok,
+    NotLogin,
+    NoMessages,
+    TooManyMessages,
+    MessageNotFound,
+    SourceNotAllowed,
+    TargetNotAllowed,
+    CannotForwardSystem,
+    OtherError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ForwardMessagesResult {
+    state: ForwardMessagesState,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ForwardEvent {
+    pub messages: Vec<MessagePlain>,
+}
+
+async fn get_source_message(
+    pool: &ConnectionPool,
+    source: &ForwardSource,
+) -> Result<Option<ChatMessageStored>, sqlx::Error> {
+    let query = match source.message_type {
+        MessageType::Private => {
+            r#"
+            SELECT
+            message_id,
+            COALESCE(content, jsonb_build_object('Text', jsonb_build_object('text', message))) as content,
+            message_from as sender_id,
+            message_to as receiver_id,
+            created_at as time,
+            expires_at
+            FROM adv_chat.private_message
+            WHERE message_id = $1
+            AND (expires_at IS NULL OR expires_at > now() at time zone 'utc')
+            "#
+        }
+        MessageType::Group => {
+            r#"
+            SELECT
+            group_message_id as message_id,
+            COALESCE(content, jsonb_build_object('Text', jsonb_build_object('text', group_message))) as content,
+            message_from as sender_id,
+            group_id as receiver_id,
+            created_at as time,
+            expires_at
+            FROM adv_chat.group_message
+            WHERE group_message_id = $1
+            AND (expires_at IS NULL OR expires_at > now() at time zone 'utc')
+            "#
+        }
+    };
+    sqlx::query_as::<_, ChatMessageStored>(query)
+        .bind(source.message_id as i64)
+        .fetch_optional(pool)
+        .await
+}
+
+async fn is_member(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    memberships: &mut HashMap<i64, bool>,
+    group_id: i64,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    if let Some(m) = memberships.get(&group_id) {
+        return Ok(*m);
+    }
+    let m = get_group_users(group_info_table, pool, group_id)
+        .await?
+        .contains(&user_id);
+    memberships.insert(group_id, m);
+    Ok(m)
+}
+
+pub async fn forward_messages(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(message_sender): State<MessageSender>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(forward_req): Json<ForwardMessagesRequest>,
+) -> Json<ForwardMessagesResult> {
+    let state = match get_user_id(session_map, forward_req.session).await {
+        Some(user_id) => {
+            let forwarded = forward(
+                &pool,
+                &group_info_table,
+                &message_sender,
+                user_id,
+                &forward_req,
+            )
+            .await;
+            match forwarded {
+                Ok(state) => state,
+                Err(e) => {
+                    debug!("failed to forward messages: {:?}", e);
+                    ForwardMessagesState::OtherError
+                }
+            }
+        }
+        None => ForwardMessagesState::NotLogin,
+    };
+    ForwardMessagesResult { state }.into()
+}
+
+async fn forward(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    message_sender: &MessageSender,
+    user_id: u64,
+    forward_req: &ForwardMessagesRequest,
+) -> Result<ForwardMessagesState, sqlx::Error> {
+    if forward_req.messages.is_empty() {
+        return Ok(ForwardMessagesState::NoMessages);
+    }
+    if forward_req.messages.len() > MAX_FORWARD_MESSAGES {
+        return Ok(ForwardMessagesState::TooManyMessages);
+    }
+    let mut memberships: HashMap<i64, bool> = HashMap::new();
+    if check_receiver(
+        pool,
+        group_info_table,
+        user_id,
+        forward_req.target_type,
+        forward_req.target_id,
+    )
+    .await
+    .is_err()
+    {
+        return Ok(ForwardMessagesState::TargetNotAllowed);
+    }
+
+    let now = now_utc();
+    let mut messages = vec![];
+    for source in &forward_req.messages {
+        let stored = match get_source_message(pool, source).await? {
+            Some(m) => m,
+            None => return Ok(ForwardMessagesState::MessageNotFound),
+        };
+        let visible = match source.message_type {
+            MessageType::Private => {
+                stored.sender_id == user_id as i64 || stored.receiver_id == user_id as i64
+            }
+            MessageType::Group => {
+                is_member(
+                    pool,
+                    group_info_table,
+                    &mut memberships,
+                    stored.receiver_id,
+                    user_id as i64,
+                )
+                .await?
+            }
+        };
+        if !visible {
+            return Ok(ForwardMessagesState::SourceNotAllowed);
+        }
+        let content = match stored.content.0 {
+            MessageContent::System { .. } => {
+                return Ok(ForwardMessagesState::CannotForwardSystem);
+            }
+            // forwarding a forwarded message keeps the first origin
+            forwarded @ MessageContent::Forwarded { .. } => forwarded,
+            content => MessageContent::Forwarded {
+                sender_id: stored.sender_id as u64,
+                time: stored.time,
+                content: Box::new(content),
+            },
+        };
+        messages.push(ChatMessage {
+            message_type: forward_req.target_type,
+            message_id: None,
+            content,
+            sender_id: user_id,
+            receiver_id: forward_req.target_id,
+            time: now,
+            expires_at: None,
+            origin: None,
+        });
+    }
+    // a forward is stored completely or not at all, and pushed as one event
+    record_and_dispatch_forwarded(pool, message_sender, messages).await?;
+    Ok(ForwardMessagesState::Ok)
+}
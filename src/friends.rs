@@ -3,7 +3,6 @@ use std::vec;
 use axum::{extract::State, Json};
 use serde::{Deserialize, Serialize};
 use tracing::debug;
-use tracing_subscriber::field::debug;
 
 use crate::{
     helper::{get_user_id, ConnectionPool, Session, SessionMap},
@@ -42,7 +41,6 @@ pub struct AddFriendRequest {
 enum AddFriendState {
     Ok,
     Error,
-    AlreadyFriend,
 }
 
 #[derive(Debug, Serialize)]
@@ -57,7 +55,7 @@ pub async fn query_friends_info(
     Json(friends_info_req): Json<FriendsInfoRequest>,
 ) -> Json<FriendsInfoResult> {
     let session = friends_info_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return FriendsInfoResult {
             state: FriendsInfoQueryState::Error,
@@ -77,7 +75,7 @@ pub async fn query_friends_info(
     };
     FriendsInfoResult {
         state: FriendsInfoQueryState::Ok,
-        info: Some(FriendsInfo { friends: friends }),
+        info: Some(FriendsInfo { friends }),
     }
     .into()
 }
@@ -102,7 +100,7 @@ async fn get_friends(pool: ConnectionPool, user_id: i64) -> Result<Vec<UserInfo>
     }
     Ok(friends)
 }
-async fn get_friend_ids(pool: &ConnectionPool, user_id: i64) -> Result<Vec<i64>, ()> {
+pub async fn get_friend_ids(pool: &ConnectionPool, user_id: i64) -> Result<Vec<i64>, ()> {
     let rows = sqlx::query_as::<_, (i64,)>(
         r#"
         SELECT UNNEST(friends)
@@ -119,6 +117,32 @@ async fn get_friend_ids(pool: &ConnectionPool, user_id: i64) -> Result<Vec<i64>,
     Ok(friend_ids)
 }
 
+/// whether the users are friends or one has written to the other, which
+/// implies both exist. only they see each other typing and reading
+pub async fn in_contact(
+    pool: &ConnectionPool,
+    user_id: i64,
+    peer_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let (in_contact,) = sqlx::query_as::<_, (bool,)>(
+        r#"
+        SELECT EXISTS (
+            SELECT 1 FROM adv_chat.user
+            WHERE user_id = $1 AND $2 = ANY(COALESCE(friends, '{}'))
+        ) OR EXISTS (
+            SELECT 1 FROM adv_chat.private_message
+            WHERE (message_from = $1 AND message_to = $2)
+            OR (message_from = $2 AND message_to = $1)
+        )
+        "#,
+    )
+    .bind(user_id)
+    .bind(peer_id)
+    .fetch_one(pool)
+    .await?;
+    Ok(in_contact)
+}
+
 async fn set_friend_ids(
     pool: &ConnectionPool,
     user_id: i64,
@@ -144,7 +168,7 @@ pub async fn user_add_friend(
     Json(add_friend_req): Json<AddFriendRequest>,
 ) -> Json<AddFriendResult> {
     let session = add_friend_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     let friend_id = add_friend_req.friend_id as i64;
     debug!("add_friends: {:?}", user_id);
     if user_id.is_none() {
@@ -225,7 +249,7 @@ pub async fn user_add_friend(
     };
     AddFriendResult {
         state: AddFriendState::Ok,
-        info: Some(FriendsInfo { friends: friends }),
+        info: Some(FriendsInfo { friends }),
     }
     .into()
 }
@@ -1,9 +1,20 @@
+use std::num::NonZeroUsize;
+
 use axum::{extract::State, Json};
+use lru::LruCache;
 use serde::{Deserialize, Serialize};
 use sqlx::FromRow;
 use tracing::debug;
 
-use crate::helper::{get_user_id, ConnectionPool, Session, SessionMap};
+use crate::{
+    cluster::{Cluster, ClusterMessage},
+    helper::{
+        get_user_id, ConnectionPool, GroupInfoTable, MessageSender, OperationState, Session,
+        SessionMap,
+    },
+    message::send_system_message,
+    message_content::SystemNotice,
+};
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct Group {
@@ -48,10 +59,11 @@ pub async fn get_group(pool: &ConnectionPool, group_id: i64) -> Result<Group, sq
 pub async fn new_group(
     State(pool): State<ConnectionPool>,
     State(session_map): State<SessionMap>,
+    State(message_sender): State<MessageSender>,
     Json(group_new_req): Json<GroupNewRequest>,
 ) -> Json<GroupNewRespone> {
     let session = group_new_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return GroupNewRespone {
             state: GroupNewState::NotLogin,
@@ -60,7 +72,7 @@ pub async fn new_group(
         .into();
     }
     let user_id = user_id.unwrap();
-    if group_new_req.group_name.len() < 1 {
+    if group_new_req.group_name.is_empty() {
         return GroupNewRespone {
             state: GroupNewState::TooShortGroupName,
             group_id: None,
@@ -74,7 +86,7 @@ pub async fn new_group(
         VALUES($1, $2, $3, now() at time zone 'utc')
         RETURNING group_id"#,
     )
-    .bind(group_new_req.group_name)
+    .bind(&group_new_req.group_name)
     .bind(user_id as i64)
     .bind(vec![user_id as i64])
     .fetch_one(&pool)
@@ -90,6 +102,23 @@ pub async fn new_group(
             .into();
         }
     };
+    if let Err(e) = record_member_joined(&pool, group_id, user_id as i64).await {
+        debug!("{:?}", e);
+    }
+    if let Err(e) = send_system_message(
+        &pool,
+        &message_sender,
+        group_id as u64,
+        user_id,
+        SystemNotice::GroupCreated {
+            user_id,
+            group_name: group_new_req.group_name,
+        },
+    )
+    .await
+    {
+        debug!("failed to record group creation: {:?}", e);
+    }
     GroupNewRespone {
         state: GroupNewState::Ok,
         group_id: Some(group_id as u64),
@@ -97,25 +126,113 @@ pub async fn new_group(
     .into()
 }
 
-pub async fn get_group_users(
+/// members of recently used groups. every membership change goes through
+/// `invalidate`, `generation` keeps a load that raced with one from being cached
+pub struct GroupCache {
+    groups: LruCache<u64, GroupMembers>,
+    generation: u64,
+    hits: u64,
+    misses: u64,
+    cluster: Cluster,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct GroupMembers {
+    users: Vec<i64>,
+    muted: Vec<i64>,
+    banned: Vec<i64>,
+}
+
+impl GroupCache {
+    pub fn new(capacity: usize, cluster: Cluster) -> Self {
+        GroupCache {
+            groups: LruCache::new(NonZeroUsize::new(capacity).unwrap()),
+            generation: 0,
+            hits: 0,
+            misses: 0,
+            cluster,
+        }
+    }
+
+    /// after a change made on this node, the other nodes forget the group too
+    pub fn invalidate(&mut self, group_id: i64) {
+        self.forget(group_id);
+        self.cluster
+            .publish(ClusterMessage::GroupChanged { group_id });
+    }
+
+    pub fn forget(&mut self, group_id: i64) {
+        self.groups.pop(&(group_id as u64));
+        self.generation += 1;
+    }
+
+    pub fn clear(&mut self) {
+        self.groups.clear();
+        self.generation += 1;
+    }
+}
+
+async fn load_group_members(
     pool: &ConnectionPool,
     group_id: i64,
-) -> Result<Vec<i64>, sqlx::Error> {
-    let g_users = sqlx::query_as::<_, (i64,)>(
+) -> Result<Option<GroupMembers>, sqlx::Error> {
+    let row = sqlx::query_as::<_, (Vec<i64>, Vec<i64>, Vec<i64>)>(
         r#"
-        SELECT UNNEST(user_list)
+        SELECT COALESCE(user_list, '{}'), COALESCE(muted_list, '{}'),
+        COALESCE(banned_list, '{}')
         FROM adv_chat.group
         WHERE group_id = $1
     "#,
     )
     .bind(group_id)
-    .fetch_all(pool)
+    .fetch_optional(pool)
     .await?;
-    let g_users: Vec<i64> = g_users.iter().map(|u| u.0).collect();
-    return Ok(g_users);
+    Ok(row.map(|(users, muted, banned)| GroupMembers {
+        users,
+        muted,
+        banned,
+    }))
+}
+
+/// members of the group, from the cache when possible. groups that don't exist
+/// have no members and are not cached
+async fn group_members(
+    group_info_table: &GroupInfoTable,
+    pool: &ConnectionPool,
+    group_id: i64,
+) -> Result<GroupMembers, sqlx::Error> {
+    let generation = {
+        let mut cache = group_info_table.lock().unwrap();
+        if let Some(members) = cache.groups.get(&(group_id as u64)).cloned() {
+            cache.hits += 1;
+            return Ok(members);
+        }
+        cache.misses += 1;
+        cache.generation
+    };
+    let members = match load_group_members(pool, group_id).await? {
+        Some(m) => m,
+        None => return Ok(GroupMembers::default()),
+    };
+    let mut cache = group_info_table.lock().unwrap();
+    if cache.generation == generation {
+        cache.groups.put(group_id as u64, members.clone());
+    }
+    Ok(members)
+}
+
+pub async fn get_group_users(
+    group_info_table: &GroupInfoTable,
+    pool: &ConnectionPool,
+    group_id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
+    Ok(group_members(group_info_table, pool, group_id)
+        .await?
+        .recipients())
 }
 
 pub async fn set_group_users(
+    group_info_table: &GroupInfoTable,
     pool: &ConnectionPool,
     group_id: i64,
     new_user_ids: &[i64],
@@ -131,25 +248,304 @@ pub async fn set_group_users(
     .bind(group_id)
     .fetch_all(pool)
     .await?;
-    return Ok(());
+    group_info_table.lock().unwrap().invalidate(group_id);
+    Ok(())
 }
 
+/// returns false if the user is already a member
 pub async fn group_add_user(
+    group_info_table: &GroupInfoTable,
     pool: &ConnectionPool,
     group_id: i64,
     new_user_id: i64,
-) -> Result<Vec<i64>, sqlx::Error> {
-    let mut g_user_ids = get_group_users(pool, group_id).await?;
+) -> Result<bool, sqlx::Error> {
+    // read from the database, the list is written back as a whole
+    let mut g_user_ids = match load_group_members(pool, group_id).await? {
+        Some(m) => m.users,
+        None => vec![],
+    };
     if g_user_ids.contains(&new_user_id) {
-        return Ok(g_user_ids);
+        return Ok(false);
     }
     g_user_ids.push(new_user_id);
-    set_group_users(pool, group_id, &g_user_ids).await?;
-    return Ok(g_user_ids);
+    set_group_users(group_info_table, pool, group_id, &g_user_ids).await?;
+    record_member_joined(pool, group_id, new_user_id).await?;
+    Ok(true)
+}
+
+/// members only see the group history from this time on
+async fn record_member_joined(
+    pool: &ConnectionPool,
+    group_id: i64,
+    user_id: i64,
+) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        INSERT INTO adv_chat.group_member
+        (group_id, user_id, joined_at)
+        VALUES($1, $2, now() at time zone 'utc')
+        ON CONFLICT (group_id, user_id)
+        DO UPDATE SET joined_at = now() at time zone 'utc'
+    "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// the group host counts as an admin even if missing from `admin_list`
+pub async fn is_group_admin(
+    pool: &ConnectionPool,
+    group_id: i64,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    let is_admin = sqlx::query_as::<_, (bool,)>(
+        r#"
+        SELECT group_host = $2 OR $2 = ANY(COALESCE(admin_list, '{}'))
+        FROM adv_chat.group
+        WHERE group_id = $1
+    "#,
+    )
+    .bind(group_id)
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(is_admin.map(|a| a.0).unwrap_or(false))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MemberStatus {
+    NotMember,
+    Member,
+    Muted,
+    Banned,
 }
 
-pub fn get_group_users_sync(pool: &ConnectionPool, group_id: i64) -> Result<Vec<i64>, sqlx::Error> {
-    let rt = tokio::runtime::Runtime::new().unwrap();
-    let users = rt.block_on(get_group_users(pool, group_id))?;
-    Ok(users)
+pub async fn get_member_status(
+    group_info_table: &GroupInfoTable,
+    pool: &ConnectionPool,
+    group_id: i64,
+    user_id: i64,
+) -> Result<MemberStatus, sqlx::Error> {
+    let members = group_members(group_info_table, pool, group_id).await?;
+    Ok(members.status_of(user_id))
+}
+
+impl GroupMembers {
+    /// members the group's messages and events go to. a ban takes the user out
+    /// of `user_list`, rows from before that still list them
+    fn recipients(&self) -> Vec<i64> {
+        self.users
+            .iter()
+            .filter(|u| !self.banned.contains(u))
+            .copied()
+            .collect()
+    }
+
+    fn status_of(&self, user_id: i64) -> MemberStatus {
+        if self.banned.contains(&user_id) {
+            MemberStatus::Banned
+        } else if !self.users.contains(&user_id) {
+            MemberStatus::NotMember
+        } else if self.muted.contains(&user_id) {
+            MemberStatus::Muted
+        } else {
+            MemberStatus::Member
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupCacheStats {
+    entries: usize,
+    hits: u64,
+    misses: u64,
+}
+
+/// how well the membership cache is doing, for monitoring
+pub async fn query_group_cache_stats(
+    State(group_info_table): State<GroupInfoTable>,
+) -> Json<GroupCacheStats> {
+    let cache = group_info_table.lock().unwrap();
+    Json(GroupCacheStats {
+        entries: cache.groups.len(),
+        hits: cache.hits,
+        misses: cache.misses,
+    })
+}
+
+#[derive(Debug, Deserialize)]
+pub struct GroupRestrictRequest {
+    session: Session,
+    group_id: u64,
+    user_id: u64,
+    enabled: bool,
+}
+
+#[derive(Debug, Serialize)]
+pub struct GroupRestrictResult {
+    state: OperationState,
+}
+
+pub async fn group_mute_member(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(restrict_req): Json<GroupRestrictRequest>,
+) -> Json<GroupRestrictResult> {
+    restrict_member(
+        &pool,
+        session_map,
+        &group_info_table,
+        restrict_req,
+        "muted_list",
+    )
+    .await
+}
+
+pub async fn group_ban_member(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(restrict_req): Json<GroupRestrictRequest>,
+) -> Json<GroupRestrictResult> {
+    restrict_member(
+        &pool,
+        session_map,
+        &group_info_table,
+        restrict_req,
+        "banned_list",
+    )
+    .await
+}
+
+/// adds the user to or removes them from `list`, admins can't be restricted
+async fn restrict_member(
+    pool: &ConnectionPool,
+    session_map: SessionMap,
+    group_info_table: &GroupInfoTable,
+    restrict_req: GroupRestrictRequest,
+    list: &'static str,
+) -> Json<GroupRestrictResult> {
+    let user_id = match get_user_id(session_map, restrict_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => {
+            return GroupRestrictResult {
+                state: OperationState::Err,
+            }
+            .into();
+        }
+    };
+    let group_id = restrict_req.group_id as i64;
+    let target_id = restrict_req.user_id as i64;
+    let allowed = match (
+        is_group_admin(pool, group_id, user_id).await,
+        is_group_admin(pool, group_id, target_id).await,
+    ) {
+        (Ok(is_admin), Ok(target_is_admin)) => is_admin && !target_is_admin,
+        (Err(e), _) | (_, Err(e)) => {
+            debug!("{:?}", e);
+            false
+        }
+    };
+    if !allowed {
+        return GroupRestrictResult {
+            state: OperationState::Err,
+        }
+        .into();
+    }
+    match set_restricted(pool, group_id, target_id, list, restrict_req.enabled).await {
+        Ok(()) => {
+            group_info_table.lock().unwrap().invalidate(group_id);
+            GroupRestrictResult {
+                state: OperationState::Ok,
+            }
+            .into()
+        }
+        Err(e) => {
+            debug!("{:?}", e);
+            GroupRestrictResult {
+                state: OperationState::Err,
+            }
+            .into()
+        }
+    }
+}
+
+/// a banned user also stops being a member, lifting the ban doesn't make them
+/// one again
+async fn set_restricted(
+    pool: &ConnectionPool,
+    group_id: i64,
+    target_id: i64,
+    list: &'static str,
+    enabled: bool,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let query = if enabled {
+        format!(
+            "UPDATE adv_chat.group SET {list} = array_append(array_remove(COALESCE({list}, '{{}}'), $2), $2) WHERE group_id = $1"
+        )
+    } else {
+        format!(
+            "UPDATE adv_chat.group SET {list} = array_remove(COALESCE({list}, '{{}}'), $2) WHERE group_id = $1"
+        )
+    };
+    sqlx::query(&query)
+        .bind(group_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await?;
+    if enabled && list == "banned_list" {
+        sqlx::query(
+            r#"
+            UPDATE adv_chat.group
+            SET user_list = array_remove(COALESCE(user_list, '{}'), $2)
+            WHERE group_id = $1
+            "#,
+        )
+        .bind(group_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await?;
+        sqlx::query(
+            r#"
+            UPDATE adv_chat.user
+            SET group_list = array_remove(COALESCE(group_list, '{}'), $1)
+            WHERE user_id = $2
+            "#,
+        )
+        .bind(group_id)
+        .bind(target_id)
+        .execute(&mut *tx)
+        .await?;
+    }
+    tx.commit().await?;
+    Ok(())
+}
+
+#[test]
+fn banned_outranks_membership() {
+    let members = GroupMembers {
+        users: vec![1, 2, 3],
+        muted: vec![2, 4],
+        banned: vec![3, 5],
+    };
+    assert_eq!(members.status_of(1), MemberStatus::Member);
+    assert_eq!(members.status_of(2), MemberStatus::Muted);
+    assert_eq!(members.status_of(3), MemberStatus::Banned);
+    assert_eq!(members.status_of(4), MemberStatus::NotMember);
+    assert_eq!(members.status_of(5), MemberStatus::Banned);
+}
+
+#[test]
+fn banned_member_gets_no_fan_out() {
+    let members = GroupMembers {
+        users: vec![1, 2, 3],
+        muted: vec![2],
+        banned: vec![3],
+    };
+    assert_eq!(members.recipients(), vec![1, 2]);
 }
@@ -1,29 +1,109 @@
-use axum::extract::ws::{Message, WebSocket};
-use futures::stream::SplitSink;
 use lru::LruCache;
+use openssl::sha::sha256;
 use serde::{Deserialize, Serialize};
 use sqlx::{pool::Pool, Postgres};
 use std::{
-    collections::HashMap,
-    sync::{mpsc::Sender, Arc, Mutex},
+    num::NonZeroUsize,
+    sync::{Arc, Mutex},
 };
-use tokio::sync::mpsc::UnboundedSender;
+use time::{OffsetDateTime, PrimitiveDateTime};
+use tracing::debug;
 use uuid::Uuid;
 
-use crate::message::ChatMessage;
+use crate::{
+    event::Connections, group_info::GroupCache, outbox::OutboxEntry, push::OfflineMessage,
+};
 
-pub type SessionMap = Arc<Mutex<LruCache<Session, u64>>>;
 pub type ConnectionPool = Pool<Postgres>;
-pub type GroupInfoTable = Arc<Mutex<LruCache<u64, Vec<u64>>>>;
-pub type UserConnectionMap = Arc<Mutex<HashMap<u64, UnboundedSender<Message>>>>;
-pub type MessageSender = Arc<Mutex<Sender<ChatMessage>>>;
+pub type GroupInfoTable = Arc<Mutex<GroupCache>>;
+pub type UserConnectionMap = Arc<Mutex<Connections>>;
+pub type MessageSender = tokio::sync::mpsc::Sender<OutboxEntry>;
+pub type PushSender = tokio::sync::mpsc::Sender<OfflineMessage>;
 #[derive(Debug, Clone, Copy, Serialize, Deserialize, Hash, PartialEq, Eq)]
 pub struct Session {
     pub session_id: Uuid,
 }
 
-pub fn get_user_id(session_map: SessionMap, session: Session) -> Option<u64> {
-    session_map.lock().unwrap().get(&session).map(|u| *u)
+impl Session {
+    /// what the `session` table keeps instead of the session itself
+    fn digest(self) -> [u8; 32] {
+        sha256(self.session_id.as_bytes())
+    }
+}
+
+/// sessions every node can look up, cached by the nodes that used them lately
+#[derive(Clone)]
+pub struct SessionMap {
+    cache: Arc<Mutex<LruCache<Session, u64>>>,
+    pool: ConnectionPool,
+}
+
+impl SessionMap {
+    pub fn new(capacity: usize, pool: ConnectionPool) -> Self {
+        SessionMap {
+            cache: Arc::new(Mutex::new(LruCache::new(
+                NonZeroUsize::new(capacity).unwrap(),
+            ))),
+            pool,
+        }
+    }
+
+    /// a new session of the user, stored before it is handed out
+    pub async fn create(&self, user_id: u64) -> Result<Session, sqlx::Error> {
+        let session = Session {
+            session_id: Uuid::new_v4(),
+        };
+        sqlx::query(
+            r#"
+            INSERT INTO adv_chat.session
+            (session_digest, user_id, created_at)
+            VALUES($1, $2, now() at time zone 'utc')
+            "#,
+        )
+        .bind(session.digest())
+        .bind(user_id as i64)
+        .execute(&self.pool)
+        .await?;
+        self.cache.lock().unwrap().put(session, user_id);
+        Ok(session)
+    }
+
+    /// the session's user, from the table when the session was made on another
+    /// node or has left the cache
+    pub async fn user_id(&self, session: Session) -> Option<u64> {
+        let cached = self.cache.lock().unwrap().get(&session).copied();
+        if cached.is_some() {
+            return cached;
+        }
+        let stored = sqlx::query_as::<_, (i64,)>(
+            "SELECT user_id FROM adv_chat.session WHERE session_digest = $1",
+        )
+        .bind(session.digest())
+        .fetch_optional(&self.pool)
+        .await;
+        match stored {
+            Ok(Some((user_id,))) => {
+                let user_id = user_id as u64;
+                self.cache.lock().unwrap().put(session, user_id);
+                Some(user_id)
+            }
+            Ok(None) => None,
+            Err(e) => {
+                debug!("failed to look up session: {:?}", e);
+                None
+            }
+        }
+    }
+}
+
+pub async fn get_user_id(session_map: SessionMap, session: Session) -> Option<u64> {
+    session_map.user_id(session).await
+}
+
+/// timestamps are stored as utc without an offset
+pub fn now_utc() -> PrimitiveDateTime {
+    let now = OffsetDateTime::now_utc();
+    PrimitiveDateTime::new(now.date(), now.time())
 }
 
 #[derive(Debug, Serialize)]
@@ -1,27 +1,38 @@
 use app_state::AppState;
-use axum::response::IntoResponse;
 use axum::routing::get;
-use axum::{
-    extract::ws::{Message, WebSocket, WebSocketUpgrade},
-    extract::State,
-    routing::post,
-    Json, Router,
-};
+use axum::{extract::State, routing::post, Json, Router};
+use bus::{BusConfig, InProcessBus};
+use cluster::{run_cluster, Cluster};
+use dispatch::{run_dispatcher, DISPATCH_QUEUE};
 use dotenvy::dotenv;
+use ephemeral::{run_sweeper, set_conversation_ttl};
+use event::Connections;
+use fallback::{event_stream, poll_events, ParkedPolls};
+use forward::forward_messages;
 use friends::{query_friends_info, user_add_friend};
-use futures::stream::SplitStream;
-use group_info::new_group;
+use group_info::{
+    group_ban_member, group_mute_member, new_group, query_group_cache_stats, GroupCache,
+};
 use hyper::http::HeaderValue;
 use hyper::Method;
-use lru::LruCache;
-use message::{message_from_client, message_processing};
+use message::message_from_client;
+use notifier::{HttpNotifier, LogNotifier};
+use outbound::query_queue_stats;
+use outbox::run_outbox_sweeper;
+use pg_bus::PgBus;
+use pin::{pin_message, query_pinned_messages, unpin_message};
+use presence::{forget_slot, query_friends_presence, run_presence_sweeper, set_presence_status};
+use push::{register_push_token, run_push, set_quiet_hours, unregister_push_token, PUSH_QUEUE};
+use redis_bus::RedisBus;
+use replay::{claim_seq_slot, run_event_writer, EVENT_QUEUE};
+use schedule::{
+    cancel_scheduled_message, edit_scheduled_message, query_scheduled_messages, run_scheduler,
+    schedule_message, Scheduler,
+};
+use search::{backfill_search_index, search_messages_client};
 use serde::{Deserialize, Serialize};
 use sqlx::postgres::PgPoolOptions;
-use std::collections::HashMap;
 use std::env;
-use std::num::NonZeroUsize;
-use std::sync::mpsc;
-use std::thread;
 use std::{
     net::SocketAddr,
     sync::{Arc, Mutex},
@@ -29,7 +40,6 @@ use std::{
 use sync_message::sync_message_client;
 use user_info::{group_add_member, query_user_groups, query_user_info, query_user_this};
 
-use futures::{sink::SinkExt, stream::StreamExt};
 use tower_http::{
     cors::{AllowOrigin, Any, CorsLayer},
     trace::TraceLayer,
@@ -38,18 +48,40 @@ use tracing::debug;
 use tracing_subscriber::{self};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
 use utils::generate_salt_and_hash;
-use uuid::Uuid;
 
 mod app_state;
+mod bus;
+mod cluster;
+mod codec;
+mod dispatch;
+mod ephemeral;
+mod event;
+mod fallback;
+mod forward;
 mod friends;
 mod group_info;
 mod helper;
 mod message;
+mod message_content;
+mod notifier;
+mod outbound;
+mod outbox;
+mod pg_bus;
+mod pin;
+mod presence;
+mod push;
+mod redis_bus;
+mod replay;
+mod schedule;
+mod search;
 mod sync_message;
+mod tunnel;
+mod typing;
 mod user_info;
 mod utils;
 
 use helper::{ConnectionPool, GroupInfoTable, Session, SessionMap, UserConnectionMap};
+use tunnel::{ws_handler, TunnelCloser, TunnelConfig};
 
 use crate::utils::check;
 
@@ -58,19 +90,9 @@ async fn main() {
     dotenv().ok();
     let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must set");
 
-    let session_cache: SessionMap = Arc::new(Mutex::new(LruCache::new(
-        NonZeroUsize::new(1024 * 1024).unwrap(),
-    )));
-
-    let group_info_table: GroupInfoTable = Arc::new(Mutex::new(LruCache::new(
-        NonZeroUsize::new(64 * 1024).unwrap(),
-    )));
-    let user_connection_map: UserConnectionMap = Arc::new(Mutex::new(HashMap::new()));
-    let (message_sender, message_receiver) = mpsc::channel();
-    let message_sender = Arc::new(Mutex::new(message_sender));
-
-    let group_info_table_ref = group_info_table.clone();
-    let user_connection_map_ref = user_connection_map.clone();
+    let (message_sender, message_receiver) = tokio::sync::mpsc::channel(DISPATCH_QUEUE);
+    let (shutdown_sender, shutdown_receiver) = tokio::sync::watch::channel(false);
+    let (push_sender, push_receiver) = tokio::sync::mpsc::channel(PUSH_QUEUE);
 
     // install global collector configured based on RUST_LOG env var.
     tracing_subscriber::registry()
@@ -85,34 +107,150 @@ async fn main() {
         .connect(&database_url)
         .await
         .expect("failed to connect database");
-    let pool_ref = pool.clone();
-    thread::spawn(move || {
-        message_processing(
-            pool_ref,
-            message_receiver,
-            user_connection_map_ref,
-        )
-    });
+    let session_cache = SessionMap::new(1024 * 1024, pool.clone());
+    let seq_slot = claim_seq_slot(&pool)
+        .await
+        .expect("failed to claim an event numbering slot")
+        .expect("every event numbering slot is taken");
+    let (cluster, cluster_receiver) = Cluster::new();
+    let group_info_table: GroupInfoTable =
+        Arc::new(Mutex::new(GroupCache::new(64 * 1024, cluster.clone())));
+    let (event_sender, event_receiver) = tokio::sync::mpsc::channel(EVENT_QUEUE);
+    let user_connection_map: UserConnectionMap = Arc::new(Mutex::new(Connections::new(
+        event_sender,
+        cluster.clone(),
+        seq_slot.slot,
+        seq_slot.last_seq,
+    )));
+    // connections recorded by the node that held the slot before
+    if let Err(e) = forget_slot(&pool, &user_connection_map, seq_slot.slot).await {
+        debug!("{:?}", e);
+    }
+    tokio::spawn(run_presence_sweeper(
+        pool.clone(),
+        user_connection_map.clone(),
+    ));
+    let dispatcher = tokio::spawn(run_dispatcher(
+        pool.clone(),
+        group_info_table.clone(),
+        message_receiver,
+        user_connection_map.clone(),
+        push_sender,
+        shutdown_receiver,
+    ));
+    // without a push service configured notifications are only logged
+    match HttpNotifier::from_env() {
+        Some(notifier) => tokio::spawn(run_push(
+            pool.clone(),
+            notifier,
+            user_connection_map.clone(),
+            push_receiver,
+        )),
+        None => tokio::spawn(run_push(
+            pool.clone(),
+            LogNotifier::default(),
+            user_connection_map.clone(),
+            push_receiver,
+        )),
+    };
+    tokio::spawn(run_sweeper(pool.clone(), user_connection_map.clone()));
+    tokio::spawn(run_event_writer(
+        pool.clone(),
+        seq_slot.slot,
+        event_receiver,
+    ));
+    tokio::spawn(backfill_search_index(pool.clone()));
+    match BusConfig::from_env() {
+        BusConfig::InProcess => tokio::spawn(run_cluster(
+            InProcessBus::default(),
+            cluster_receiver,
+            cluster.node(),
+            user_connection_map.clone(),
+            group_info_table.clone(),
+        )),
+        BusConfig::Postgres => tokio::spawn(run_cluster(
+            PgBus::new(pool.clone()),
+            cluster_receiver,
+            cluster.node(),
+            user_connection_map.clone(),
+            group_info_table.clone(),
+        )),
+        BusConfig::Redis(url) => tokio::spawn(run_cluster(
+            RedisBus::open(&url).expect("invalid REDIS_URL"),
+            cluster_receiver,
+            cluster.node(),
+            user_connection_map.clone(),
+            group_info_table.clone(),
+        )),
+    };
+    let scheduler = Scheduler::default();
+    let tunnel_closer = TunnelCloser::new();
+    tokio::spawn(run_scheduler(
+        pool.clone(),
+        group_info_table.clone(),
+        message_sender.clone(),
+        scheduler.clone(),
+    ));
+    tokio::spawn(run_outbox_sweeper(pool.clone(), message_sender.clone()));
     let state = AppState {
         sesson_map: session_cache,
         db_pool: pool.clone(),
         group_info_table: group_info_table.clone(),
         user_connection_map: user_connection_map.clone(),
-        message_sender: message_sender,
+        message_sender,
+        scheduler,
+        tunnel_config: TunnelConfig::from_env(),
+        cluster,
+        parked_polls: ParkedPolls::default(),
+        tunnel_shutdown: tunnel_closer.handle(),
     };
+    // monitoring endpoints, kept off the public listener
+    let internal = Router::new()
+        .route("/tunnel/stats", get(query_queue_stats))
+        .route("/group/cache/stats", get(query_group_cache_stats))
+        .with_state(state.clone());
+    let internal_addr = env::var("INTERNAL_ADDR")
+        .ok()
+        .and_then(|a| a.parse::<SocketAddr>().ok())
+        .unwrap_or_else(|| SocketAddr::from(([127, 0, 0, 1], 3001)));
+    let internal = axum::Server::bind(&internal_addr).serve(internal.into_make_service());
+    tokio::spawn(async {
+        if let Err(e) = internal.await {
+            debug!("{:?}", e);
+        }
+    });
     let app = Router::new()
         .route("/user/register", post(user_register))
         .route("/user/login", post(user_login))
         .route("/user/info", post(query_user_info))
         .route("/user/this", post(query_user_this))
         .route("/tunnel", get(ws_handler))
+        .route("/events", get(event_stream))
+        .route("/events/poll", post(poll_events))
         .route("/message", post(message_from_client))
+        .route("/message/forward", post(forward_messages))
+        .route("/message/ttl", post(set_conversation_ttl))
+        .route("/message/pin", post(pin_message))
+        .route("/message/unpin", post(unpin_message))
+        .route("/message/pinned", post(query_pinned_messages))
+        .route("/message/schedule", post(schedule_message))
+        .route("/message/scheduled", post(query_scheduled_messages))
+        .route("/message/scheduled/cancel", post(cancel_scheduled_message))
+        .route("/message/scheduled/edit", post(edit_scheduled_message))
         .route("/user/message/sync", post(sync_message_client))
+        .route("/user/message/search", post(search_messages_client))
         .route("/user/groups", post(query_user_groups))
         .route("/user/friends", post(query_friends_info))
         .route("/user/add/friend", post(user_add_friend))
+        .route("/user/friends/presence", post(query_friends_presence))
+        .route("/user/presence/status", post(set_presence_status))
+        .route("/user/push/register", post(register_push_token))
+        .route("/user/push/unregister", post(unregister_push_token))
+        .route("/user/push/quiet", post(set_quiet_hours))
         .route("/group/add/member", post(group_add_member))
         .route("/group/new", post(new_group))
+        .route("/group/mute/member", post(group_mute_member))
+        .route("/group/ban/member", post(group_ban_member))
         .layer(
             CorsLayer::new()
                 .allow_origin(AllowOrigin::list(
@@ -125,10 +263,27 @@ async fn main() {
         .layer(TraceLayer::new_for_http())
         .with_state(state);
     let addr = SocketAddr::from(([0, 0, 0, 0], 3000));
-    axum::Server::bind(&addr)
-        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
-        .await
-        .unwrap();
+    let (stopping_sender, mut stopping) = tokio::sync::watch::channel(false);
+    let server = tokio::spawn(
+        axum::Server::bind(&addr)
+            .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+            .with_graceful_shutdown(async move {
+                let _ = stopping.changed().await;
+            }),
+    );
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        debug!("{:?}", e);
+    }
+    // no new connections from here on. messages already stored still reach the
+    // users connected right now, tunnels and sse streams end once they have
+    // written them, and the server is done after that
+    let _ = stopping_sender.send(true);
+    let _ = shutdown_sender.send(true);
+    if let Err(e) = dispatcher.await {
+        debug!("{:?}", e);
+    }
+    tunnel_closer.close().await;
+    server.await.unwrap().unwrap();
 }
 
 #[derive(Debug, Serialize)]
@@ -195,8 +350,6 @@ async fn user_register(
     let (hash, salt) = generate_salt_and_hash(&user_reg_req.password);
     let salt: String = salt.iter().collect();
 
-    let uuid = Uuid::new_v4();
-    let session_id = Session { session_id: uuid };
     let user_id: i64 = match sqlx::query_as::<_, (i64,)>(
         "INSERT INTO adv_chat.user
         (user_name, user_passwd_hash, salt, avatar, created_at)
@@ -220,7 +373,18 @@ async fn user_register(
             .into();
         }
     };
-    sesson_map.lock().unwrap().put(session_id, user_id as u64);
+    let session_id = match sesson_map.create(user_id as u64).await {
+        Ok(session_id) => session_id,
+        Err(e) => {
+            debug!("failed to store session: {:?}", e);
+            return UserRegisterResultInfo {
+                state: UserRegisterState::OtherError,
+                session_info: None,
+                user_id: Some(user_id as u64),
+            }
+            .into();
+        }
+    };
     UserRegisterResultInfo {
         state: UserRegisterState::Ok,
         session_info: Some(session_id),
@@ -279,9 +443,17 @@ async fn user_login(
         }
     };
     if check(&password, &salt, user_passwd_hash) {
-        let uuid = Uuid::new_v4();
-        let session_id = Session { session_id: uuid };
-        sesson_map.lock().unwrap().put(session_id, user_id as u64);
+        let session_id = match sesson_map.create(user_id).await {
+            Ok(session_id) => session_id,
+            Err(e) => {
+                debug!("failed to store session: {:?}", e);
+                return UserLoginInfo {
+                    state: UserLoginState::OtherError,
+                    session_info: None,
+                }
+                .into();
+            }
+        };
         UserLoginInfo {
             state: UserLoginState::Success,
             session_info: Some(session_id),
@@ -295,53 +467,3 @@ async fn user_login(
         .into()
     }
 }
-
-async fn ws_handler(
-    ws: WebSocketUpgrade,
-    State(sesson_map): State<SessionMap>,
-    State(user_connection_map): State<UserConnectionMap>,
-) -> impl IntoResponse {
-    debug!("{:?}", ws);
-    ws.on_upgrade(move |socket| handle_socket(socket, sesson_map, user_connection_map))
-}
-async fn handle_socket(
-    socket: WebSocket,
-    session_map: SessionMap,
-    user_connection_map: UserConnectionMap,
-) {
-    let (mut sender, mut receiver) = socket.split();
-    let (sender_unbouned, mut receiver_unbounded) =
-        tokio::sync::mpsc::unbounded_channel::<Message>();
-    let session: Session = match check_token(&mut receiver).await {
-        Ok(s) => s,
-        Err(_) => {
-            return;
-        }
-    };
-    debug!("{:?}", session);
-    let user_id = match session_map.lock().unwrap().get(&session) {
-        Some(user_id) => *user_id,
-        None => {
-            return;
-        }
-    };
-    user_connection_map
-        .lock()
-        .unwrap()
-        .insert(user_id, sender_unbouned);
-    while let Some(r) = receiver_unbounded.recv().await {
-        sender.send(r).await;
-    }
-}
-
-async fn check_token(stream: &mut SplitStream<WebSocket>) -> Result<Session, ()> {
-    if let Some(Ok(m)) = stream.next().await {
-        if let Message::Text(t) = m {
-            serde_json::from_str(&t).map_err(|e| debug!("{:?}", e))
-        } else {
-            Err(())
-        }
-    } else {
-        Err(())
-    }
-}
@@ -1,203 +1,440 @@
 use axum::{extract::State, Json};
-use futures::{sink::SinkExt, stream::StreamExt};
-use sqlx::FromRow;
-use std::{
-    sync::mpsc::{Receiver, RecvError},
-    thread,
-};
-use tokio::task;
-use tracing_subscriber::field::debug;
+use sqlx::{types::Json as SqlJson, FromRow, PgConnection};
 
 use serde::{Deserialize, Serialize};
-use time::{OffsetDateTime, PrimitiveDateTime, UtcOffset};
+use time::PrimitiveDateTime;
 use tracing::debug;
 
 use crate::{
-    group_info::{get_group_users, get_group_users_sync},
-    helper::{
-        ConnectionPool, GroupInfoTable, MessageSender, Session, SessionMap, UserConnectionMap,
-    },
-    UserLoginRequest,
+    ephemeral::{check_ttl, conversation_ttl},
+    group_info::{get_member_status, MemberStatus},
+    helper::{now_utc, ConnectionPool, GroupInfoTable, MessageSender, Session, SessionMap},
+    message_content::{ContentError, MessageContent, SystemNotice, MAX_CONTENT_LENGTH},
+    outbox::record_and_dispatch,
+    search::search_document,
+    tunnel::ConnectionId,
+    user_info::user_exists,
 };
 
-#[derive(Debug, Serialize)]
+#[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MessagePlain {
     message_type: MessageType,
+    message_id: Option<u64>,
     user_id: u64,
     group_id: Option<u64>,
-    content: String,
+    content: MessageContent,
+    expires_at: Option<PrimitiveDateTime>,
 }
 
-#[derive(Debug, Serialize, Deserialize, Clone)]
+impl MessagePlain {
+    pub fn expired(&self, now: PrimitiveDateTime) -> bool {
+        self.expires_at.is_some_and(|t| t <= now)
+    }
+}
+
+impl From<&ChatMessage> for MessagePlain {
+    fn from(msg: &ChatMessage) -> Self {
+        MessagePlain {
+            message_type: msg.message_type,
+            message_id: msg.message_id,
+            user_id: msg.sender_id,
+            group_id: match msg.message_type {
+                MessageType::Private => None,
+                MessageType::Group => Some(msg.receiver_id),
+            },
+            content: msg.content.clone(),
+            expires_at: msg.expires_at,
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, Hash)]
 pub enum MessageType {
     Private,
     Group,
 }
 
+/// a private chat or a group, private participants are ordered by user id
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Conversation {
+    Private(i64, i64),
+    Group(i64),
+}
+
+impl Conversation {
+    pub fn private(a: i64, b: i64) -> Self {
+        Conversation::Private(a.min(b), a.max(b))
+    }
+
+    pub fn of(message: &ChatMessage) -> Self {
+        match message.message_type {
+            MessageType::Private => {
+                Conversation::private(message.sender_id as i64, message.receiver_id as i64)
+            }
+            MessageType::Group => Conversation::Group(message.receiver_id as i64),
+        }
+    }
+
+    pub fn key(&self) -> String {
+        match self {
+            Conversation::Private(a, b) => format!("p:{}:{}", a, b),
+            Conversation::Group(g) => format!("g:{}", g),
+        }
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct ChatMessage {
     pub message_type: MessageType,
-    pub content: String,
+    /// set once the message is stored
+    pub message_id: Option<u64>,
+    pub content: MessageContent,
     pub sender_id: u64,
     pub receiver_id: u64,
     pub time: PrimitiveDateTime,
+    pub expires_at: Option<PrimitiveDateTime>,
+    /// the tunnel connection the message was sent from, which gets no echo
+    #[serde(skip)]
+    pub origin: Option<ConnectionId>,
 }
 
 #[derive(Debug, Serialize, Deserialize, FromRow)]
 pub struct ChatMessageStored {
-    pub content: String,
+    pub message_id: i64,
+    pub content: SqlJson<MessageContent>,
     pub sender_id: i64,
     pub receiver_id: i64,
     pub time: PrimitiveDateTime,
+    pub expires_at: Option<PrimitiveDateTime>,
+}
+
+impl ChatMessageStored {
+    pub fn into_message(self, message_type: MessageType) -> ChatMessage {
+        ChatMessage {
+            message_type,
+            message_id: Some(self.message_id as u64),
+            content: self.content.0,
+            sender_id: self.sender_id as u64,
+            receiver_id: self.receiver_id as u64,
+            time: self.time,
+            expires_at: self.expires_at,
+            origin: None,
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatMessageRequest {
     pub message_type: MessageType,
-    pub content: String,
+    pub content: MessageContent,
     pub reciver_id: u64,
     pub seesion: Session,
+    /// overrides the disappearing time of the conversation for this message
+    pub ttl_seconds: Option<u32>,
 }
 
-#[derive(Debug, Serialize)]
-enum ChatMessageInfoState {
+#[derive(Debug, Serialize, Clone, Copy)]
+pub enum ChatMessageInfoState {
     Ok,
     WrongToken,
+    EmptyContent,
+    ContentTooLong,
+    InvalidContent(ContentError),
+    InvalidTtl,
+    ReceiverNotFound,
+    NotGroupMember,
+    Muted,
+    Banned,
     OtherError,
 }
 
+impl From<ContentError> for ChatMessageInfoState {
+    fn from(e: ContentError) -> Self {
+        match e {
+            ContentError::Empty => ChatMessageInfoState::EmptyContent,
+            ContentError::TooLong => ChatMessageInfoState::ContentTooLong,
+            e => ChatMessageInfoState::InvalidContent(e),
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum SendRejection {
+    ReceiverNotFound,
+    NotGroupMember,
+    Muted,
+    Banned,
+    OtherError,
+}
+
+impl From<SendRejection> for ChatMessageInfoState {
+    fn from(e: SendRejection) -> Self {
+        match e {
+            SendRejection::ReceiverNotFound => ChatMessageInfoState::ReceiverNotFound,
+            SendRejection::NotGroupMember => ChatMessageInfoState::NotGroupMember,
+            SendRejection::Muted => ChatMessageInfoState::Muted,
+            SendRejection::Banned => ChatMessageInfoState::Banned,
+            SendRejection::OtherError => ChatMessageInfoState::OtherError,
+        }
+    }
+}
+
+/// whether a stored message with the id belongs to the conversation
+pub async fn in_conversation(
+    pool: &ConnectionPool,
+    conversation: Conversation,
+    message_id: u64,
+) -> Result<bool, sqlx::Error> {
+    let query = match conversation {
+        Conversation::Private(a, b) => sqlx::query_as::<_, (bool,)>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM adv_chat.private_message
+                WHERE message_id = $1
+                AND LEAST(message_from, message_to) = $2
+                AND GREATEST(message_from, message_to) = $3
+            )
+            "#,
+        )
+        .bind(message_id as i64)
+        .bind(a)
+        .bind(b),
+        Conversation::Group(g) => sqlx::query_as::<_, (bool,)>(
+            r#"
+            SELECT EXISTS (
+                SELECT 1 FROM adv_chat.group_message
+                WHERE group_message_id = $1 AND group_id = $2
+            )
+            "#,
+        )
+        .bind(message_id as i64)
+        .bind(g),
+    };
+    Ok(query.fetch_one(pool).await?.0)
+}
+
+/// private messages need an existing receiver, group messages a sender in the
+/// group's `user_list` who is neither muted nor banned
+pub async fn check_receiver(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    sender_id: u64,
+    message_type: MessageType,
+    receiver_id: u64,
+) -> Result<(), SendRejection> {
+    let checked = match message_type {
+        MessageType::Private => user_exists(pool, receiver_id as i64).await.map(|exists| {
+            if exists {
+                Ok(())
+            } else {
+                Err(SendRejection::ReceiverNotFound)
+            }
+        }),
+        MessageType::Group => {
+            get_member_status(group_info_table, pool, receiver_id as i64, sender_id as i64)
+                .await
+                .map(|status| match status {
+                    MemberStatus::Member => Ok(()),
+                    MemberStatus::NotMember => Err(SendRejection::NotGroupMember),
+                    MemberStatus::Muted => Err(SendRejection::Muted),
+                    MemberStatus::Banned => Err(SendRejection::Banned),
+                })
+        }
+    };
+    match checked {
+        Ok(checked) => checked,
+        Err(e) => {
+            debug!("failed to check receiver: {:?}", e);
+            Err(SendRejection::OtherError)
+        }
+    }
+}
+
 #[derive(Debug, Serialize)]
 pub struct ChatMessageInfo {
     state: ChatMessageInfoState,
+    message_id: Option<u64>,
 }
 
 pub async fn message_from_client(
     State(sesson_map): State<SessionMap>,
     State(message_sender): State<MessageSender>,
     State(pool): State<ConnectionPool>,
+    State(group_info_table): State<GroupInfoTable>,
     Json(message_req): Json<ChatMessageRequest>,
 ) -> Json<ChatMessageInfo> {
-    let user_id = match sesson_map.lock().unwrap().get(&message_req.seesion) {
-        Some(user_id) => *user_id,
+    let user_id = match sesson_map.user_id(message_req.seesion).await {
+        Some(user_id) => user_id,
         None => {
             return ChatMessageInfo {
                 state: ChatMessageInfoState::WrongToken,
+                message_id: None,
             }
             .into();
         }
     };
-    let now = OffsetDateTime::now_utc();
-    let message = ChatMessage {
+    let outgoing = OutgoingMessage {
         message_type: message_req.message_type,
-        content: message_req.content,
-        sender_id: user_id,
         receiver_id: message_req.reciver_id,
-        time: PrimitiveDateTime::new(now.date(), now.time()),
+        content: message_req.content,
+        ttl_seconds: message_req.ttl_seconds,
     };
-    record_message(&pool, message.clone()).await.unwrap();
-    message_sender.lock().unwrap().send(message).unwrap();
-    ChatMessageInfo {
-        state: ChatMessageInfoState::Ok,
+    match submit_message(
+        &pool,
+        &group_info_table,
+        &message_sender,
+        user_id,
+        None,
+        outgoing,
+    )
+    .await
+    {
+        Ok(message) => ChatMessageInfo {
+            state: ChatMessageInfoState::Ok,
+            message_id: message.message_id,
+        }
+        .into(),
+        Err(state) => ChatMessageInfo {
+            state,
+            message_id: None,
+        }
+        .into(),
     }
-    .into()
-}
-
-pub fn message_processing(
-    pool: ConnectionPool,
-    receiver: Receiver<ChatMessage>,
-    user_connection_map: UserConnectionMap,
-) {
-    loop {
-        match receiver.recv() {
-            Ok(msg) => match msg.message_type {
-                MessageType::Private => {
-                    let user_id = msg.receiver_id;
-                    let sender_id = msg.sender_id;
-                    debug!("{:?}", msg);
-                    if let Some(may_sender) = user_connection_map.lock().unwrap().get_mut(&user_id)
-                    {
-                        if let Err(err) = may_sender.send(axum::extract::ws::Message::Text(
-                            serde_json::to_string(&MessagePlain {
-                                message_type: MessageType::Private,
-                                user_id: sender_id,
-                                group_id: None,
-                                content: msg.content,
-                            })
-                            .unwrap(),
-                        )) {
-                            debug!("{:?}", err);
-                        }
-                    }
-                }
-                MessageType::Group => {
-                    let group_id = msg.receiver_id;
-                    let sender_id = msg.sender_id;
-                    let group_user_ids = match get_group_users_sync(&pool, group_id as i64) {
-                        Ok(g) => g,
-                        Err(e) => {
-                            debug!("{:?}", e);
-                            vec![]
-                        }
-                    };
-                    debug!("{:?}", group_user_ids);
-                    for uid in group_user_ids {
-                        if let Some(may_sender) =
-                            user_connection_map.lock().unwrap().get_mut(&(uid as u64))
-                        {
-                            if let Err(err) = may_sender.send(axum::extract::ws::Message::Text(
-                                serde_json::to_string(&MessagePlain {
-                                    message_type: MessageType::Group,
-                                    user_id: sender_id,
-                                    group_id: Some(group_id),
-                                    content: msg.content.clone(),
-                                })
-                                .unwrap(),
-                            )) {
-                                debug!("{:?}", err);
-                            }
-                        }
-                    }
-                }
-            },
-            Err(e) => {
-                debug!("{:?}", e);
-            }
+}
+
+/// a message as a client sends it, over http or the tunnel
+#[derive(Debug)]
+pub struct OutgoingMessage {
+    pub message_type: MessageType,
+    pub receiver_id: u64,
+    pub content: MessageContent,
+    pub ttl_seconds: Option<u32>,
+}
+
+/// validates, stores and dispatches a message sent by a client
+pub async fn submit_message(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    message_sender: &MessageSender,
+    user_id: u64,
+    origin: Option<ConnectionId>,
+    outgoing: OutgoingMessage,
+) -> Result<ChatMessage, ChatMessageInfoState> {
+    let OutgoingMessage {
+        message_type,
+        receiver_id,
+        content,
+        ttl_seconds,
+    } = outgoing;
+    content.validate()?;
+    if let Some(ttl) = ttl_seconds {
+        if !check_ttl(ttl) {
+            return Err(ChatMessageInfoState::InvalidTtl);
+        }
+    }
+    check_receiver(pool, group_info_table, user_id, message_type, receiver_id).await?;
+    let now = now_utc();
+    let message = ChatMessage {
+        message_type,
+        message_id: None,
+        content,
+        sender_id: user_id,
+        receiver_id,
+        time: now,
+        expires_at: ttl_seconds.map(|ttl| now + time::Duration::seconds(ttl as i64)),
+        origin,
+    };
+    match record_and_dispatch(pool, message_sender, message).await {
+        Ok(m) => Ok(m),
+        Err(e) => {
+            debug!("failed to record message: {:?}", e);
+            Err(ChatMessageInfoState::OtherError)
         }
     }
 }
 
-async fn record_message(pool: &ConnectionPool, message: ChatMessage) -> Result<(), sqlx::Error> {
-    match message.message_type {
+/// records a server generated notice in the group history and pushes it to the members
+pub async fn send_system_message(
+    pool: &ConnectionPool,
+    message_sender: &MessageSender,
+    group_id: u64,
+    actor_id: u64,
+    notice: SystemNotice,
+) -> Result<(), sqlx::Error> {
+    let message = ChatMessage {
+        message_type: MessageType::Group,
+        message_id: None,
+        content: MessageContent::System { notice },
+        sender_id: actor_id,
+        receiver_id: group_id,
+        time: now_utc(),
+        expires_at: None,
+        origin: None,
+    };
+    record_and_dispatch(pool, message_sender, message).await?;
+    Ok(())
+}
+
+/// stores the message on the caller's connection, which may be in a transaction,
+/// and returns it with its id. messages without their own expiry get the
+/// disappearing time of the conversation
+pub async fn insert_message(
+    conn: &mut PgConnection,
+    mut message: ChatMessage,
+) -> Result<ChatMessage, sqlx::Error> {
+    if message.expires_at.is_none() {
+        if let Some(ttl) = conversation_ttl(&mut *conn, Conversation::of(&message)).await? {
+            message.expires_at = Some(message.time + time::Duration::seconds(ttl as i64));
+        }
+    }
+    // server built content like forwards may be longer than what clients can send
+    let plain_text: String = message
+        .content
+        .plain_text()
+        .chars()
+        .take(MAX_CONTENT_LENGTH)
+        .collect();
+    let document = search_document(&plain_text);
+    let message_id = match message.message_type {
         MessageType::Group => {
-            sqlx::query(
+            sqlx::query_as::<_, (i64,)>(
                 r#"
                 INSERT INTO adv_chat.group_message
-                (message_from, group_id, group_message, created_at)
-                VALUES($1, $2, $3, $4)
+                (message_from, group_id, group_message, content, search_vector, created_at, expires_at)
+                VALUES($1, $2, $3, $4, to_tsvector('simple', $5), $6, $7)
+                RETURNING group_message_id
             "#,
             )
             .bind(message.sender_id as i64)
             .bind(message.receiver_id as i64)
-            .bind(message.content)
+            .bind(&plain_text)
+            .bind(SqlJson(&message.content))
+            .bind(&document)
             .bind(message.time)
-            .execute(pool)
-            .await?;
+            .bind(message.expires_at)
+            .fetch_one(&mut *conn)
+            .await?
         }
         MessageType::Private => {
-            sqlx::query(
+            sqlx::query_as::<_, (i64,)>(
                 r#"
             INSERT INTO adv_chat.private_message
-            (message_from, message_to, message, created_at)
-            VALUES($1, $2, $3, $4::timestamp)
+            (message_from, message_to, message, content, search_vector, created_at, expires_at)
+            VALUES($1, $2, $3, $4, to_tsvector('simple', $5), $6::timestamp, $7)
+            RETURNING message_id
         "#,
             )
             .bind(message.sender_id as i64)
             .bind(message.receiver_id as i64)
-            .bind(message.content)
+            .bind(&plain_text)
+            .bind(SqlJson(&message.content))
+            .bind(&document)
             .bind(message.time)
-            .execute(pool)
-            .await?;
+            .bind(message.expires_at)
+            .fetch_one(&mut *conn)
+            .await?
         }
-    }
-    Ok(())
+    };
+    message.message_id = Some(message_id.0 as u64);
+    Ok(message)
 }
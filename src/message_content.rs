@@ -0,0 +1,264 @@
+use serde::{Deserialize, Serialize};
+use time::PrimitiveDateTime;
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub struct Attachment {
+    pub url: String,
+    pub name: String,
+    pub mime_type: String,
+    pub size: u64,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum SystemNotice {
+    GroupCreated { user_id: u64, group_name: String },
+    MemberJoined { user_id: u64 },
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
+pub enum MessageContent {
+    Text {
+        text: String,
+    },
+    Markdown {
+        text: String,
+    },
+    Image {
+        attachment: Attachment,
+        width: Option<u32>,
+        height: Option<u32>,
+    },
+    File {
+        attachment: Attachment,
+    },
+    Voice {
+        attachment: Attachment,
+        duration_ms: u32,
+    },
+    Location {
+        latitude: f64,
+        longitude: f64,
+        name: Option<String>,
+    },
+    System {
+        notice: SystemNotice,
+    },
+    /// a copy of another message, keeping its original sender and time
+    Forwarded {
+        sender_id: u64,
+        time: PrimitiveDateTime,
+        content: Box<MessageContent>,
+    },
+}
+
+#[derive(Debug, Serialize, Clone, Copy, PartialEq, Eq)]
+pub enum ContentError {
+    Empty,
+    TooLong,
+    InvalidAttachment,
+    InvalidDuration,
+    InvalidCoordinates,
+    SystemNotAllowed,
+    ForwardedNotAllowed,
+}
+
+/// same as the `varchar(4096)` columns the plain text is stored in
+pub const MAX_CONTENT_LENGTH: usize = 4096;
+// voice messages longer than this are expected to be sent as files
+const MAX_VOICE_DURATION_MS: u32 = 10 * 60 * 1000;
+const MAX_URL_LENGTH: usize = 2048;
+const MAX_FILE_NAME_LENGTH: usize = 255;
+/// 127 characters each for type and subtype, rfc 6838
+const MAX_MIME_TYPE_LENGTH: usize = 255;
+
+impl MessageContent {
+    /// checks a payload sent by a client, system notices can only be created by the server
+    pub fn validate(&self) -> Result<(), ContentError> {
+        self.validate_payload()?;
+        if self.plain_text().chars().count() > MAX_CONTENT_LENGTH {
+            return Err(ContentError::TooLong);
+        }
+        Ok(())
+    }
+
+    fn validate_payload(&self) -> Result<(), ContentError> {
+        match self {
+            MessageContent::Text { text } | MessageContent::Markdown { text } => {
+                if text.trim().is_empty() {
+                    return Err(ContentError::Empty);
+                }
+                Ok(())
+            }
+            MessageContent::Image { attachment, .. } | MessageContent::File { attachment } => {
+                check_attachment(attachment)
+            }
+            MessageContent::Voice {
+                attachment,
+                duration_ms,
+            } => {
+                check_attachment(attachment)?;
+                if *duration_ms == 0 || *duration_ms > MAX_VOICE_DURATION_MS {
+                    return Err(ContentError::InvalidDuration);
+                }
+                Ok(())
+            }
+            MessageContent::Location {
+                latitude,
+                longitude,
+                ..
+            } => {
+                if !(-90.0..=90.0).contains(latitude) || !(-180.0..=180.0).contains(longitude) {
+                    return Err(ContentError::InvalidCoordinates);
+                }
+                Ok(())
+            }
+            MessageContent::System { .. } => Err(ContentError::SystemNotAllowed),
+            MessageContent::Forwarded { .. } => Err(ContentError::ForwardedNotAllowed),
+        }
+    }
+
+    /// text stored next to the structured content, used for previews
+    pub fn plain_text(&self) -> String {
+        match self {
+            MessageContent::Text { text } | MessageContent::Markdown { text } => text.clone(),
+            MessageContent::Image { .. } => "[image]".to_string(),
+            MessageContent::File { attachment } => format!("[file] {}", attachment.name),
+            MessageContent::Voice { duration_ms, .. } => {
+                format!("[voice] {}s", duration_ms.div_ceil(1000))
+            }
+            MessageContent::Location { name, .. } => match name {
+                Some(name) => format!("[location] {}", name),
+                None => "[location]".to_string(),
+            },
+            MessageContent::System { notice } => match notice {
+                SystemNotice::GroupCreated {
+                    user_id,
+                    group_name,
+                } => format!("{} created the group \"{}\"", user_id, group_name),
+                SystemNotice::MemberJoined { user_id } => format!("{} joined the group", user_id),
+            },
+            MessageContent::Forwarded { content, .. } => {
+                format!("[forwarded] {}", content.plain_text())
+            }
+        }
+    }
+}
+
+fn check_attachment(attachment: &Attachment) -> Result<(), ContentError> {
+    if attachment.url.trim().is_empty()
+        || attachment.name.trim().is_empty()
+        || attachment.mime_type.trim().is_empty()
+    {
+        return Err(ContentError::InvalidAttachment);
+    }
+    if attachment.url.len() > MAX_URL_LENGTH
+        || attachment.name.chars().count() > MAX_FILE_NAME_LENGTH
+        || attachment.mime_type.len() > MAX_MIME_TYPE_LENGTH
+    {
+        return Err(ContentError::InvalidAttachment);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_content_validate() {
+    let attachment = Attachment {
+        url: "https://files.frontend.org/a.ogg".to_string(),
+        name: "a.ogg".to_string(),
+        mime_type: "audio/ogg".to_string(),
+        size: 1024,
+    };
+    assert!(MessageContent::Text {
+        text: "你好".to_string()
+    }
+    .validate()
+    .is_ok());
+    assert_eq!(
+        MessageContent::Markdown {
+            text: " \n\t".to_string()
+        }
+        .validate(),
+        Err(ContentError::Empty)
+    );
+    assert_eq!(
+        MessageContent::Text {
+            text: "字".repeat(MAX_CONTENT_LENGTH + 1)
+        }
+        .validate(),
+        Err(ContentError::TooLong)
+    );
+    assert!(MessageContent::Text {
+        text: "字".repeat(MAX_CONTENT_LENGTH)
+    }
+    .validate()
+    .is_ok());
+    assert_eq!(
+        MessageContent::Voice {
+            attachment: attachment.clone(),
+            duration_ms: 0,
+        }
+        .validate(),
+        Err(ContentError::InvalidDuration)
+    );
+    assert_eq!(
+        MessageContent::File {
+            attachment: Attachment {
+                url: " ".to_string(),
+                ..attachment.clone()
+            },
+        }
+        .validate(),
+        Err(ContentError::InvalidAttachment)
+    );
+    assert_eq!(
+        MessageContent::Image {
+            attachment: Attachment {
+                url: format!("https://files.frontend.org/{}", "a".repeat(MAX_URL_LENGTH)),
+                ..attachment.clone()
+            },
+            width: None,
+            height: None,
+        }
+        .validate(),
+        Err(ContentError::InvalidAttachment)
+    );
+    assert_eq!(
+        MessageContent::File {
+            attachment: Attachment {
+                mime_type: format!("audio/{}", "x".repeat(MAX_MIME_TYPE_LENGTH)),
+                ..attachment
+            },
+        }
+        .validate(),
+        Err(ContentError::InvalidAttachment)
+    );
+    assert_eq!(
+        MessageContent::Location {
+            latitude: 91.0,
+            longitude: 0.0,
+            name: None,
+        }
+        .validate(),
+        Err(ContentError::InvalidCoordinates)
+    );
+    assert_eq!(
+        MessageContent::System {
+            notice: SystemNotice::MemberJoined { user_id: 100000 },
+        }
+        .validate(),
+        Err(ContentError::SystemNotAllowed)
+    );
+}
+
+#[test]
+fn test_content_json() {
+    let content = MessageContent::Text {
+        text: "hello".to_string(),
+    };
+    let json = serde_json::to_string(&content).unwrap();
+    assert_eq!(json, r#"{"Text":{"text":"hello"}}"#);
+    assert_eq!(
+        serde_json::from_str::<MessageContent>(&json).unwrap(),
+        content
+    );
+}
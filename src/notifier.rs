@@ -0,0 +1,337 @@
+use std::{
+    env, fmt,
+    future::Future,
+    sync::{Arc, Mutex},
+};
+
+use hyper::{
+    body::{self, Body},
+    client::HttpConnector,
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    Client, Method, Request, StatusCode,
+};
+use hyper_tls::HttpsConnector;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tracing::info;
+
+/// where a device receives push notifications
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    Fcm,
+    Apns,
+}
+
+impl Platform {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Platform::Fcm => "fcm",
+            Platform::Apns => "apns",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "fcm" => Some(Platform::Fcm),
+            "apns" => Some(Platform::Apns),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Device {
+    pub platform: Platform,
+    pub token: String,
+}
+
+/// what a device shows, possibly standing for several messages
+#[derive(Debug, Serialize, Clone, PartialEq, Eq)]
+pub struct PushNotification {
+    pub title: String,
+    pub body: String,
+    /// a newer notification with the same key replaces the shown one
+    pub collapse_key: String,
+    pub unread: u32,
+}
+
+#[derive(Debug)]
+pub enum NotifyError {
+    /// no endpoint is configured for the platform
+    Unsupported(Platform),
+    /// the token is no longer valid and should be forgotten
+    Gone,
+    Http(hyper::Error),
+    Status(StatusCode),
+}
+
+impl fmt::Display for NotifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NotifyError::Unsupported(platform) => {
+                write!(f, "{} is not configured", platform.as_str())
+            }
+            NotifyError::Gone => write!(f, "token no longer valid"),
+            NotifyError::Http(e) => write!(f, "{}", e),
+            NotifyError::Status(status) => write!(f, "push service answered {}", status),
+        }
+    }
+}
+
+impl From<hyper::Error> for NotifyError {
+    fn from(e: hyper::Error) -> Self {
+        NotifyError::Http(e)
+    }
+}
+
+/// sends push notifications to devices
+pub trait Notifier: Send + Sync + 'static {
+    fn notify(
+        &self,
+        device: &Device,
+        notification: &PushNotification,
+    ) -> impl Future<Output = Result<(), NotifyError>> + Send;
+}
+
+#[derive(Debug, Clone)]
+pub struct PushEndpoint {
+    url: String,
+    /// fcm server key or apns provider token
+    credential: Option<String>,
+}
+
+impl PushEndpoint {
+    fn from_env(url: &str, credential: &str) -> Option<Self> {
+        Some(PushEndpoint {
+            url: env::var(url).ok()?.trim_end_matches('/').to_string(),
+            credential: env::var(credential).ok(),
+        })
+    }
+}
+
+/// fcm legacy http and apns style requests, sent to whatever url is configured
+/// so a local mock can stand in for the real services
+pub struct HttpNotifier {
+    client: Client<HttpsConnector<HttpConnector>>,
+    fcm: Option<PushEndpoint>,
+    apns: Option<PushEndpoint>,
+}
+
+impl HttpNotifier {
+    pub fn new(fcm: Option<PushEndpoint>, apns: Option<PushEndpoint>) -> Self {
+        HttpNotifier {
+            client: Client::builder().build(HttpsConnector::new()),
+            fcm,
+            apns,
+        }
+    }
+
+    /// from `PUSH_FCM_URL`/`PUSH_FCM_KEY` and `PUSH_APNS_URL`/`PUSH_APNS_TOKEN`,
+    /// `None` when neither url is set
+    pub fn from_env() -> Option<Self> {
+        let fcm = PushEndpoint::from_env("PUSH_FCM_URL", "PUSH_FCM_KEY");
+        let apns = PushEndpoint::from_env("PUSH_APNS_URL", "PUSH_APNS_TOKEN");
+        if fcm.is_none() && apns.is_none() {
+            return None;
+        }
+        Some(HttpNotifier::new(fcm, apns))
+    }
+
+    fn request(&self, device: &Device, notification: &PushNotification) -> Option<Request<Body>> {
+        let request = Request::builder()
+            .method(Method::POST)
+            .header(CONTENT_TYPE, "application/json");
+        let request = match device.platform {
+            Platform::Fcm => {
+                let endpoint = self.fcm.as_ref()?;
+                let payload = json!({
+                    "to": device.token,
+                    "collapse_key": notification.collapse_key,
+                    "notification": {
+                        "title": notification.title,
+                        "body": notification.body,
+                    },
+                    "data": { "unread": notification.unread },
+                });
+                let request = request.uri(&endpoint.url);
+                let request = match &endpoint.credential {
+                    Some(key) => request.header(AUTHORIZATION, format!("key={}", key)),
+                    None => request,
+                };
+                request.body(Body::from(payload.to_string()))
+            }
+            Platform::Apns => {
+                let endpoint = self.apns.as_ref()?;
+                let payload = json!({
+                    "aps": {
+                        "alert": {
+                            "title": notification.title,
+                            "body": notification.body,
+                        },
+                        "badge": notification.unread,
+                    },
+                });
+                let request = request
+                    .uri(format!("{}/3/device/{}", endpoint.url, device.token))
+                    .header("apns-push-type", "alert")
+                    .header("apns-collapse-id", &notification.collapse_key);
+                let request = match &endpoint.credential {
+                    Some(token) => request.header(AUTHORIZATION, format!("bearer {}", token)),
+                    None => request,
+                };
+                request.body(Body::from(payload.to_string()))
+            }
+        };
+        request.ok()
+    }
+}
+
+impl Notifier for HttpNotifier {
+    async fn notify(
+        &self,
+        device: &Device,
+        notification: &PushNotification,
+    ) -> Result<(), NotifyError> {
+        let request = self
+            .request(device, notification)
+            .ok_or(NotifyError::Unsupported(device.platform))?;
+        let response = self.client.request(request).await?;
+        let status = response.status();
+        if status == StatusCode::NOT_FOUND || status == StatusCode::GONE {
+            return Err(NotifyError::Gone);
+        }
+        if !status.is_success() {
+            return Err(NotifyError::Status(status));
+        }
+        // fcm answers 200 and names invalid tokens in the body
+        if device.platform == Platform::Fcm {
+            let body = body::to_bytes(response.into_body()).await?;
+            let body = String::from_utf8_lossy(&body);
+            if body.contains("NotRegistered") || body.contains("InvalidRegistration") {
+                return Err(NotifyError::Gone);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// only logs, and keeps what it was asked to send
+#[derive(Debug, Clone, Default)]
+pub struct LogNotifier {
+    sent: Arc<Mutex<Vec<(Device, PushNotification)>>>,
+}
+
+impl LogNotifier {
+    #[cfg(test)]
+    pub fn sent(&self) -> Vec<(Device, PushNotification)> {
+        self.sent.lock().unwrap().clone()
+    }
+}
+
+impl Notifier for LogNotifier {
+    async fn notify(
+        &self,
+        device: &Device,
+        notification: &PushNotification,
+    ) -> Result<(), NotifyError> {
+        info!(
+            "push to {} device: {:?}",
+            device.platform.as_str(),
+            notification
+        );
+        self.sent
+            .lock()
+            .unwrap()
+            .push((device.clone(), notification.clone()));
+        Ok(())
+    }
+}
+
+#[tokio::test]
+async fn http_notifier_talks_to_a_mock() {
+    use axum::{extract::Path, http::HeaderMap, routing::post, Router};
+
+    let captured: Arc<Mutex<Vec<String>>> = Arc::default();
+    let fcm_captured = captured.clone();
+    let apns_captured = captured.clone();
+    let app = Router::new()
+        .route(
+            "/fcm/send",
+            post(move |headers: HeaderMap, payload: String| async move {
+                let key = headers[AUTHORIZATION].to_str().unwrap().to_string();
+                fcm_captured
+                    .lock()
+                    .unwrap()
+                    .push(format!("{} {}", key, payload));
+                if payload.contains("stale") {
+                    r#"{"results":[{"error":"NotRegistered"}]}"#
+                } else {
+                    r#"{"results":[{"message_id":"1"}]}"#
+                }
+            }),
+        )
+        .route(
+            "/3/device/:token",
+            post(
+                move |Path(token): Path<String>, headers: HeaderMap| async move {
+                    let collapse_id = headers["apns-collapse-id"].to_str().unwrap().to_string();
+                    apns_captured
+                        .lock()
+                        .unwrap()
+                        .push(format!("{} {}", token, collapse_id));
+                    if token == "stale" {
+                        StatusCode::GONE
+                    } else {
+                        StatusCode::OK
+                    }
+                },
+            ),
+        );
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(
+        axum::Server::from_tcp(listener)
+            .unwrap()
+            .serve(app.into_make_service()),
+    );
+
+    let notifier = HttpNotifier::new(
+        Some(PushEndpoint {
+            url: format!("http://{}/fcm/send", addr),
+            credential: Some("server-key".to_string()),
+        }),
+        Some(PushEndpoint {
+            url: format!("http://{}", addr),
+            credential: None,
+        }),
+    );
+    let notification = PushNotification {
+        title: "New message".to_string(),
+        body: "hi".to_string(),
+        collapse_key: "new_messages".to_string(),
+        unread: 1,
+    };
+    let device = |platform, token: &str| Device {
+        platform,
+        token: token.to_string(),
+    };
+    notifier
+        .notify(&device(Platform::Fcm, "abc"), &notification)
+        .await
+        .unwrap();
+    notifier
+        .notify(&device(Platform::Apns, "def"), &notification)
+        .await
+        .unwrap();
+    for platform in [Platform::Fcm, Platform::Apns] {
+        let gone = notifier
+            .notify(&device(platform, "stale"), &notification)
+            .await;
+        assert!(matches!(gone, Err(NotifyError::Gone)));
+    }
+    let captured = captured.lock().unwrap();
+    assert!(captured[0].starts_with("key=server-key "));
+    assert!(captured[0].contains(r#""to":"abc""#));
+    assert!(captured[0].contains(r#""collapse_key":"new_messages""#));
+    assert_eq!(captured[1], "def new_messages");
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::VecDeque,
+    sync::{Arc, Mutex},
+};
+
+use axum::{extract::State, Json};
+use serde::Serialize;
+use tokio::sync::Notify;
+
+use crate::{
+    helper::UserConnectionMap,
+    tunnel::{ConnectionId, ServerFrame},
+};
+
+/// what to do when a connection's queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    /// make room by dropping the oldest typing or presence event, disconnect
+    /// only when everything queued has to be delivered
+    DropOldest,
+    /// close the connection right away, the client resumes once it catches up
+    Disconnect,
+}
+
+/// frames waiting to be written to one tunnel
+#[derive(Debug)]
+pub struct OutboundQueue {
+    state: Mutex<QueueState>,
+    notify: Notify,
+    capacity: usize,
+    policy: QueuePolicy,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    frames: VecDeque<ServerFrame>,
+    /// set once the queue overflowed, the connection is closed after that
+    overflowed: bool,
+    peak: usize,
+    dropped: u64,
+}
+
+/// frames that may be lost without the client missing anything it needs
+fn droppable(frame: &ServerFrame) -> bool {
+    matches!(frame, ServerFrame::Event { event, .. } if !event.is_durable())
+}
+
+impl OutboundQueue {
+    pub fn new(capacity: usize, policy: QueuePolicy) -> Arc<Self> {
+        Arc::new(OutboundQueue {
+            state: Mutex::new(QueueState::default()),
+            notify: Notify::new(),
+            capacity,
+            policy,
+        })
+    }
+
+    /// queues a frame, returns false once the connection has to be closed
+    pub fn push(&self, frame: ServerFrame) -> bool {
+        let mut state = self.state.lock().unwrap();
+        if state.overflowed {
+            return false;
+        }
+        if state.frames.len() >= self.capacity {
+            let victim = match self.policy {
+                QueuePolicy::DropOldest => state.frames.iter().position(droppable),
+                QueuePolicy::Disconnect => None,
+            };
+            match victim {
+                Some(i) => {
+                    state.frames.remove(i);
+                    state.dropped += 1;
+                }
+                None if self.policy == QueuePolicy::DropOldest && droppable(&frame) => {
+                    state.dropped += 1;
+                    return true;
+                }
+                None => {
+                    state.overflowed = true;
+                    state.frames.clear();
+                    drop(state);
+                    self.notify.notify_one();
+                    return false;
+                }
+            }
+        }
+        state.frames.push_back(frame);
+        state.peak = state.peak.max(state.frames.len());
+        drop(state);
+        self.notify.notify_one();
+        true
+    }
+
+    /// the next frame, `None` once the queue overflowed
+    pub async fn recv(&self) -> Option<ServerFrame> {
+        loop {
+            {
+                let mut state = self.state.lock().unwrap();
+                if state.overflowed {
+                    return None;
+                }
+                if let Some(frame) = state.frames.pop_front() {
+                    return Some(frame);
+                }
+            }
+            self.notify.notified().await;
+        }
+    }
+
+    /// everything queued right now, nothing once the queue overflowed
+    pub fn drain(&self) -> Vec<ServerFrame> {
+        let mut state = self.state.lock().unwrap();
+        if state.overflowed {
+            return vec![];
+        }
+        state.frames.drain(..).collect()
+    }
+
+    pub fn overflowed(&self) -> bool {
+        self.state.lock().unwrap().overflowed
+    }
+
+    fn stats(&self) -> (usize, usize, u64) {
+        let state = self.state.lock().unwrap();
+        (state.frames.len(), state.peak, state.dropped)
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct QueueStats {
+    connection_id: ConnectionId,
+    device: Option<String>,
+    depth: usize,
+    peak: usize,
+    dropped: u64,
+}
+
+/// queue depth of every open tunnel, for monitoring
+pub async fn query_queue_stats(
+    State(user_connection_map): State<UserConnectionMap>,
+) -> Json<Vec<QueueStats>> {
+    let map = user_connection_map.lock().unwrap();
+    let stats = map
+        .connections()
+        .map(|(connection_id, connection)| {
+            let (depth, peak, dropped) = connection.sender.stats();
+            QueueStats {
+                connection_id,
+                device: connection.device.clone(),
+                depth,
+                peak,
+                dropped,
+            }
+        })
+        .collect();
+    Json(stats)
+}
+
+#[test]
+fn full_queue_drops_ephemeral_events_first() {
+    let typing: crate::event::ServerEvent = serde_json::from_str(
+        r#"{"Typing":{"message_type":"Group","conversation_id":1,"user_id":2,"typing":true,"expires_in_ms":6000}}"#,
+    )
+    .unwrap();
+    let typing = ServerFrame::Event {
+        seq: None,
+        event: typing,
+    };
+    let queue = OutboundQueue::new(2, QueuePolicy::DropOldest);
+    assert!(queue.push(typing.clone()));
+    assert!(queue.push(ServerFrame::Pong { nonce: 1 }));
+    // the typing event makes room
+    assert!(queue.push(ServerFrame::Pong { nonce: 2 }));
+    assert_eq!(queue.stats(), (2, 2, 1));
+    // a new typing event is dropped itself
+    assert!(queue.push(typing));
+    assert_eq!(queue.stats(), (2, 2, 2));
+    // nothing left to drop
+    assert!(!queue.push(ServerFrame::Pong { nonce: 3 }));
+    assert!(queue.overflowed());
+
+    let queue = OutboundQueue::new(1, QueuePolicy::Disconnect);
+    assert!(queue.push(ServerFrame::Pong { nonce: 1 }));
+    assert!(!queue.push(ServerFrame::Pong { nonce: 2 }));
+}
@@ -0,0 +1,233 @@
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlJson, PgConnection};
+use tracing::debug;
+
+use crate::{
+    helper::{ConnectionPool, MessageSender},
+    message::{insert_message, ChatMessage},
+};
+
+/// an entry is handed over again when it wasn't marked dispatched this long after
+/// it was last handed to a dispatcher, e.g. because that process died
+const LEASE: &str = "30 seconds";
+const SWEEP_INTERVAL: Duration = Duration::from_secs(10);
+const SWEEP_BATCH: i64 = 500;
+const RETENTION: &str = "1 day";
+
+/// what an outbox entry pushes, a single message keeps the json of the message
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Outgoing {
+    Message(ChatMessage),
+    /// messages forwarded together, pushed as one event
+    Forwarded {
+        forwarded: Vec<ChatMessage>,
+    },
+}
+
+impl Outgoing {
+    /// the message whose conversation the entry goes to, forwards have one target
+    pub fn lead(&self) -> &ChatMessage {
+        match self {
+            Outgoing::Message(message) => message,
+            Outgoing::Forwarded { forwarded } => &forwarded[0],
+        }
+    }
+}
+
+/// a stored message the dispatcher still has to deliver
+#[derive(Debug)]
+pub struct OutboxEntry {
+    pub outbox_id: i64,
+    pub outgoing: Outgoing,
+}
+
+/// stores the message and its outbox entry in one transaction, then hands it to
+/// the dispatcher. delivery is at least once, clients drop repeated message ids
+pub async fn record_and_dispatch(
+    pool: &ConnectionPool,
+    message_sender: &MessageSender,
+    message: ChatMessage,
+) -> Result<ChatMessage, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let entry = record_with_entry(&mut tx, message).await?;
+    tx.commit().await?;
+    let message = entry.outgoing.lead().clone();
+    dispatch(message_sender, entry).await;
+    Ok(message)
+}
+
+/// stores forwarded messages, all or none, with one outbox entry for the batch
+pub async fn record_and_dispatch_forwarded(
+    pool: &ConnectionPool,
+    message_sender: &MessageSender,
+    messages: Vec<ChatMessage>,
+) -> Result<(), sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    let mut forwarded = vec![];
+    for message in messages {
+        forwarded.push(insert_message(&mut tx, message).await?);
+    }
+    let entry = add_entry(&mut tx, Outgoing::Forwarded { forwarded }).await?;
+    tx.commit().await?;
+    dispatch(message_sender, entry).await;
+    Ok(())
+}
+
+/// stores the message and its outbox entry in the caller's transaction, which
+/// has to commit before the entry is dispatched
+pub async fn record_with_entry(
+    conn: &mut PgConnection,
+    message: ChatMessage,
+) -> Result<OutboxEntry, sqlx::Error> {
+    let message = insert_message(conn, message).await?;
+    add_entry(conn, Outgoing::Message(message)).await
+}
+
+async fn add_entry(
+    conn: &mut PgConnection,
+    outgoing: Outgoing,
+) -> Result<OutboxEntry, sqlx::Error> {
+    let (outbox_id,) = sqlx::query_as::<_, (i64,)>(
+        r#"
+        INSERT INTO adv_chat.message_outbox
+        (message, origin, created_at, claimed_at)
+        VALUES($1, $2, now() at time zone 'utc', now() at time zone 'utc')
+        RETURNING outbox_id
+        "#,
+    )
+    .bind(SqlJson(&outgoing))
+    .bind(outgoing.lead().origin.map(|o| o as i64))
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(OutboxEntry {
+        outbox_id,
+        outgoing,
+    })
+}
+
+pub async fn dispatch(message_sender: &MessageSender, entry: OutboxEntry) {
+    // the sweeper picks it up if the dispatcher is gone
+    if let Err(e) = message_sender.send(entry).await {
+        debug!("{:?}", e);
+    }
+}
+
+pub async fn mark_dispatched(pool: &ConnectionPool, outbox_id: i64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        UPDATE adv_chat.message_outbox
+        SET dispatched_at = now() at time zone 'utc'
+        WHERE outbox_id = $1
+        "#,
+    )
+    .bind(outbox_id)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// hands entries nobody delivered to this node's dispatcher, and drops old
+/// delivered ones
+pub async fn run_outbox_sweeper(pool: ConnectionPool, message_sender: MessageSender) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        match claim_stale(&pool).await {
+            Ok(entries) => {
+                for entry in entries {
+                    debug!("redispatching outbox entry {}", entry.outbox_id);
+                    if message_sender.send(entry).await.is_err() {
+                        return;
+                    }
+                }
+            }
+            Err(e) => debug!("failed to sweep the outbox: {:?}", e),
+        }
+        if let Err(e) = prune_dispatched(&pool).await {
+            debug!("failed to prune the outbox: {:?}", e);
+        }
+    }
+}
+
+/// several nodes may sweep at once, each entry is claimed by one of them
+async fn claim_stale(pool: &ConnectionPool) -> Result<Vec<OutboxEntry>, sqlx::Error> {
+    let mut rows = sqlx::query_as::<_, (i64, SqlJson<Outgoing>, Option<i64>)>(
+        r#"
+        UPDATE adv_chat.message_outbox
+        SET claimed_at = now() at time zone 'utc'
+        WHERE outbox_id IN (
+            SELECT outbox_id
+            FROM adv_chat.message_outbox
+            WHERE dispatched_at IS NULL
+            AND claimed_at < now() at time zone 'utc' - $1::interval
+            ORDER BY outbox_id
+            LIMIT $2
+            FOR UPDATE SKIP LOCKED
+        )
+        RETURNING outbox_id, message, origin
+        "#,
+    )
+    .bind(LEASE)
+    .bind(SWEEP_BATCH)
+    .fetch_all(pool)
+    .await?;
+    rows.sort_by_key(|r| r.0);
+    Ok(rows
+        .into_iter()
+        .map(|(outbox_id, outgoing, origin)| {
+            let mut outgoing = outgoing.0;
+            if let Outgoing::Message(message) = &mut outgoing {
+                message.origin = origin.map(|o| o as u64);
+            }
+            OutboxEntry {
+                outbox_id,
+                outgoing,
+            }
+        })
+        .collect())
+}
+
+async fn prune_dispatched(pool: &ConnectionPool) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        DELETE FROM adv_chat.message_outbox
+        WHERE dispatched_at < now() at time zone 'utc' - $1::interval
+        "#,
+    )
+    .bind(RETENTION)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+#[test]
+fn entries_stored_before_forward_batches_still_load() {
+    let message = ChatMessage {
+        message_type: crate::message::MessageType::Private,
+        message_id: Some(5),
+        content: crate::message_content::MessageContent::Text {
+            text: "hi".to_string(),
+        },
+        sender_id: 1,
+        receiver_id: 2,
+        time: crate::helper::now_utc(),
+        expires_at: None,
+        origin: None,
+    };
+    let stored = serde_json::to_string(&message).unwrap();
+    match serde_json::from_str(&stored).unwrap() {
+        Outgoing::Message(m) => assert_eq!(m.message_id, Some(5)),
+        other => panic!("unexpected {:?}", other),
+    }
+    let forwarded = serde_json::to_string(&Outgoing::Forwarded {
+        forwarded: vec![message.clone(), message],
+    })
+    .unwrap();
+    match serde_json::from_str(&forwarded).unwrap() {
+        Outgoing::Forwarded { forwarded } => assert_eq!(forwarded.len(), 2),
+        other => panic!("unexpected {:?}", other),
+    }
+}
@@ -0,0 +1,153 @@
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+use sqlx::{postgres::PgListener, types::Json as SqlJson};
+use tracing::debug;
+
+use crate::{
+    bus::{BusError, BusSubscriber, Envelope, EventBus},
+    helper::ConnectionPool,
+};
+
+const CHANNEL: &str = "adv_chat_cluster";
+/// postgres rejects notifications of 8000 bytes or more, bigger ones go
+/// through `adv_chat.cluster_event`
+const MAX_INLINE_PAYLOAD: usize = 7900;
+const RETENTION: &str = "1 hour";
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10 * 60);
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Notification {
+    Inline(Envelope),
+    Stored(i64),
+}
+
+/// `LISTEN/NOTIFY` on the database every node already uses
+pub struct PgBus {
+    pool: ConnectionPool,
+    last_prune: Instant,
+}
+
+impl PgBus {
+    pub fn new(pool: ConnectionPool) -> Self {
+        PgBus {
+            pool,
+            last_prune: Instant::now(),
+        }
+    }
+
+    async fn prune_stored(&mut self) -> Result<(), sqlx::Error> {
+        if self.last_prune.elapsed() < PRUNE_INTERVAL {
+            return Ok(());
+        }
+        self.last_prune = Instant::now();
+        sqlx::query(
+            r#"
+            DELETE FROM adv_chat.cluster_event
+            WHERE created_at < now() at time zone 'utc' - $1::interval
+            "#,
+        )
+        .bind(RETENTION)
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
+
+pub struct PgSubscriber {
+    pool: ConnectionPool,
+    listener: PgListener,
+}
+
+impl EventBus for PgBus {
+    type Subscriber = PgSubscriber;
+
+    async fn publish(&mut self, envelope: &Envelope) -> Result<(), BusError> {
+        if let Err(e) = self.prune_stored().await {
+            debug!("failed to prune cluster events: {:?}", e);
+        }
+        let inline = Notification::Inline(envelope.clone());
+        let payload = serde_json::to_string(&inline).unwrap();
+        let payload = if payload.len() <= MAX_INLINE_PAYLOAD {
+            payload
+        } else {
+            let (event_id,) = sqlx::query_as::<_, (i64,)>(
+                r#"
+                INSERT INTO adv_chat.cluster_event (message, created_at)
+                VALUES($1, now() at time zone 'utc')
+                RETURNING event_id
+                "#,
+            )
+            .bind(SqlJson(envelope))
+            .fetch_one(&self.pool)
+            .await?;
+            serde_json::to_string(&Notification::Stored(event_id)).unwrap()
+        };
+        sqlx::query("SELECT pg_notify($1, $2)")
+            .bind(CHANNEL)
+            .bind(payload)
+            .execute(&self.pool)
+            .await?;
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<PgSubscriber, BusError> {
+        let mut listener = PgListener::connect_with(&self.pool).await?;
+        listener.listen(CHANNEL).await?;
+        Ok(PgSubscriber {
+            pool: self.pool.clone(),
+            listener,
+        })
+    }
+}
+
+impl PgSubscriber {
+    async fn load_stored(&self, event_id: i64) -> Result<Option<Envelope>, sqlx::Error> {
+        let row = sqlx::query_as::<_, (SqlJson<Envelope>,)>(
+            r#"
+            SELECT message
+            FROM adv_chat.cluster_event
+            WHERE event_id = $1
+            "#,
+        )
+        .bind(event_id)
+        .fetch_optional(&self.pool)
+        .await?;
+        Ok(row.map(|r| r.0 .0))
+    }
+}
+
+impl BusSubscriber for PgSubscriber {
+    async fn next(&mut self) -> Result<Option<Envelope>, BusError> {
+        loop {
+            // `None` means the connection was lost, it reconnects on the next call
+            let notification = match self.listener.try_recv().await? {
+                Some(n) => n,
+                None => return Ok(None),
+            };
+            match serde_json::from_str(notification.payload()) {
+                Ok(Notification::Inline(envelope)) => return Ok(Some(envelope)),
+                Ok(Notification::Stored(event_id)) => match self.load_stored(event_id).await? {
+                    Some(envelope) => return Ok(Some(envelope)),
+                    None => debug!("cluster event {} already pruned", event_id),
+                },
+                Err(e) => debug!("invalid cluster notification: {:?}", e),
+            }
+        }
+    }
+}
+
+#[test]
+fn notification_round_trip() {
+    let envelope = Envelope {
+        node: uuid::Uuid::new_v4(),
+        message: crate::cluster::ClusterMessage::GroupChanged { group_id: 7 },
+    };
+    let payload = serde_json::to_string(&Notification::Inline(envelope.clone())).unwrap();
+    match serde_json::from_str(&payload).unwrap() {
+        Notification::Inline(Envelope { node, .. }) => assert_eq!(node, envelope.node),
+        other => panic!("unexpected {:?}", other),
+    }
+    let payload = serde_json::to_string(&Notification::Stored(3)).unwrap();
+    assert!(payload.len() <= MAX_INLINE_PAYLOAD);
+}
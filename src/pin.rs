@@ -0,0 +1,425 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlJson, FromRow, PgConnection};
+use time::PrimitiveDateTime;
+use tracing::debug;
+
+use crate::{
+    event::{push_event, ServerEvent},
+    group_info::{get_group_users, is_group_admin},
+    helper::{get_user_id, ConnectionPool, GroupInfoTable, Session, SessionMap, UserConnectionMap},
+    message::{Conversation, MessageType},
+    message_content::MessageContent,
+};
+
+const MAX_PINNED_PER_CONVERSATION: i64 = 10;
+
+#[derive(Debug, Deserialize)]
+pub struct PinMessageRequest {
+    session: Session,
+    message_type: MessageType,
+    message_id: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub enum PinMessageState {
+    Ok,
+    NotLogin,
+    MessageNotFound,
+    PermissionDenied,
+    TooManyPinned,
+    NotPinned,
+    OtherError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinMessageResult {
+    state: PinMessageState,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PinEvent {
+    message_type: MessageType,
+    /// the group id, or the other participant of a private chat
+    conversation_id: u64,
+    message_id: u64,
+    user_id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct PinnedMessagesRequest {
+    session: Session,
+    message_type: MessageType,
+    conversation_id: u64,
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct PinnedMessage {
+    message_id: i64,
+    sender_id: i64,
+    content: SqlJson<MessageContent>,
+    time: PrimitiveDateTime,
+    pinned_by: i64,
+    pinned_at: PrimitiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PinnedMessagesResult {
+    state: PinMessageState,
+    messages: Option<Vec<PinnedMessage>>,
+}
+
+async fn find_conversation(
+    pool: &ConnectionPool,
+    message_type: &MessageType,
+    message_id: i64,
+) -> Result<Option<Conversation>, sqlx::Error> {
+    match message_type {
+        MessageType::Private => {
+            let row = sqlx::query_as::<_, (i64, i64)>(
+                r#"
+                SELECT message_from, message_to
+                FROM adv_chat.private_message
+                WHERE message_id = $1
+                "#,
+            )
+            .bind(message_id)
+            .fetch_optional(pool)
+            .await?;
+            Ok(row.map(|(from, to)| Conversation::private(from, to)))
+        }
+        MessageType::Group => {
+            let row = sqlx::query_as::<_, (i64,)>(
+                r#"
+                SELECT group_id
+                FROM adv_chat.group_message
+                WHERE group_message_id = $1
+                "#,
+            )
+            .bind(message_id)
+            .fetch_optional(pool)
+            .await?;
+            Ok(row.map(|g| Conversation::Group(g.0)))
+        }
+    }
+}
+
+async fn can_pin(
+    pool: &ConnectionPool,
+    conversation: Conversation,
+    user_id: i64,
+) -> Result<bool, sqlx::Error> {
+    match conversation {
+        Conversation::Private(a, b) => Ok(user_id == a || user_id == b),
+        Conversation::Group(group_id) => is_group_admin(pool, group_id, user_id).await,
+    }
+}
+
+async fn count_pinned(
+    conn: &mut PgConnection,
+    conversation: Conversation,
+) -> Result<i64, sqlx::Error> {
+    let (group_id, user_a, user_b) = conversation_keys(conversation);
+    let count = sqlx::query_as::<_, (i64,)>(
+        r#"
+        SELECT COUNT(*)
+        FROM adv_chat.pinned_message
+        WHERE group_id IS NOT DISTINCT FROM $1
+        AND user_a IS NOT DISTINCT FROM $2
+        AND user_b IS NOT DISTINCT FROM $3
+        "#,
+    )
+    .bind(group_id)
+    .bind(user_a)
+    .bind(user_b)
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(count.0)
+}
+
+/// `None` when the conversation already has as many pins as it may have
+async fn insert_pin(
+    pool: &ConnectionPool,
+    conversation: Conversation,
+    message_id: i64,
+    user_id: i64,
+) -> Result<Option<u64>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    // concurrent pins of one conversation are counted and added one after another
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext($1))")
+        .bind(conversation.key())
+        .execute(&mut *tx)
+        .await?;
+    if count_pinned(&mut tx, conversation).await? >= MAX_PINNED_PER_CONVERSATION {
+        return Ok(None);
+    }
+    let (group_id, user_a, user_b) = conversation_keys(conversation);
+    let inserted = sqlx::query(
+        r#"
+        INSERT INTO adv_chat.pinned_message
+        (is_group, message_id, group_id, user_a, user_b, pinned_by, created_at)
+        VALUES($1, $2, $3, $4, $5, $6, now() at time zone 'utc')
+        ON CONFLICT (is_group, message_id) DO NOTHING
+        "#,
+    )
+    .bind(group_id.is_some())
+    .bind(message_id)
+    .bind(group_id)
+    .bind(user_a)
+    .bind(user_b)
+    .bind(user_id)
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(Some(inserted.rows_affected()))
+}
+
+fn conversation_keys(conversation: Conversation) -> (Option<i64>, Option<i64>, Option<i64>) {
+    match conversation {
+        Conversation::Private(a, b) => (None, Some(a), Some(b)),
+        Conversation::Group(g) => (Some(g), None, None),
+    }
+}
+
+async fn notify_pin(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    user_connection_map: &UserConnectionMap,
+    conversation: Conversation,
+    message_id: u64,
+    user_id: u64,
+    pinned: bool,
+) {
+    let recipients: Vec<(u64, MessageType, u64)> = match conversation {
+        Conversation::Private(a, b) => vec![
+            (a as u64, MessageType::Private, b as u64),
+            (b as u64, MessageType::Private, a as u64),
+        ],
+        Conversation::Group(group_id) => {
+            match get_group_users(group_info_table, pool, group_id).await {
+                Ok(users) => users
+                    .iter()
+                    .map(|u| (*u as u64, MessageType::Group, group_id as u64))
+                    .collect(),
+                Err(e) => {
+                    debug!("{:?}", e);
+                    vec![]
+                }
+            }
+        }
+    };
+    for (recipient, message_type, conversation_id) in recipients {
+        let pin_event = PinEvent {
+            message_type,
+            conversation_id,
+            message_id,
+            user_id,
+        };
+        let event = if pinned {
+            ServerEvent::MessagePinned(pin_event)
+        } else {
+            ServerEvent::MessageUnpinned(pin_event)
+        };
+        push_event(user_connection_map, recipient, &event);
+    }
+}
+
+pub async fn pin_message(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(user_connection_map): State<UserConnectionMap>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(pin_req): Json<PinMessageRequest>,
+) -> Json<PinMessageResult> {
+    let state = set_pinned(
+        &pool,
+        &session_map,
+        &group_info_table,
+        &user_connection_map,
+        pin_req,
+        true,
+    )
+    .await;
+    PinMessageResult { state }.into()
+}
+
+pub async fn unpin_message(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(user_connection_map): State<UserConnectionMap>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(pin_req): Json<PinMessageRequest>,
+) -> Json<PinMessageResult> {
+    let state = set_pinned(
+        &pool,
+        &session_map,
+        &group_info_table,
+        &user_connection_map,
+        pin_req,
+        false,
+    )
+    .await;
+    PinMessageResult { state }.into()
+}
+
+async fn set_pinned(
+    pool: &ConnectionPool,
+    session_map: &SessionMap,
+    group_info_table: &GroupInfoTable,
+    user_connection_map: &UserConnectionMap,
+    pin_req: PinMessageRequest,
+    pinned: bool,
+) -> PinMessageState {
+    let user_id = match get_user_id(session_map.clone(), pin_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => return PinMessageState::NotLogin,
+    };
+    let message_id = pin_req.message_id as i64;
+    let conversation = match find_conversation(pool, &pin_req.message_type, message_id).await {
+        Ok(Some(c)) => c,
+        Ok(None) => return PinMessageState::MessageNotFound,
+        Err(e) => {
+            debug!("{:?}", e);
+            return PinMessageState::OtherError;
+        }
+    };
+    match can_pin(pool, conversation, user_id).await {
+        Ok(true) => {}
+        Ok(false) => return PinMessageState::PermissionDenied,
+        Err(e) => {
+            debug!("{:?}", e);
+            return PinMessageState::OtherError;
+        }
+    }
+    let is_group = matches!(conversation, Conversation::Group(_));
+    let changed = if pinned {
+        match insert_pin(pool, conversation, message_id, user_id).await {
+            Ok(Some(inserted)) => Ok(inserted),
+            Ok(None) => return PinMessageState::TooManyPinned,
+            Err(e) => Err(e),
+        }
+    } else {
+        sqlx::query(
+            r#"
+            DELETE FROM adv_chat.pinned_message
+            WHERE is_group = $1 AND message_id = $2
+            "#,
+        )
+        .bind(is_group)
+        .bind(message_id)
+        .execute(pool)
+        .await
+        .map(|r| r.rows_affected())
+    };
+    match changed {
+        Ok(0) if !pinned => return PinMessageState::NotPinned,
+        Ok(0) => return PinMessageState::Ok,
+        Ok(_) => {}
+        Err(e) => {
+            debug!("{:?}", e);
+            return PinMessageState::OtherError;
+        }
+    }
+    notify_pin(
+        pool,
+        group_info_table,
+        user_connection_map,
+        conversation,
+        message_id as u64,
+        user_id as u64,
+        pinned,
+    )
+    .await;
+    PinMessageState::Ok
+}
+
+pub async fn query_pinned_messages(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(pinned_req): Json<PinnedMessagesRequest>,
+) -> Json<PinnedMessagesResult> {
+    let user_id = match get_user_id(session_map, pinned_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => {
+            return PinnedMessagesResult {
+                state: PinMessageState::NotLogin,
+                messages: None,
+            }
+            .into();
+        }
+    };
+    let conversation_id = pinned_req.conversation_id as i64;
+    let messages = match pinned_req.message_type {
+        MessageType::Private => {
+            let user_a = user_id.min(conversation_id);
+            let user_b = user_id.max(conversation_id);
+            sqlx::query_as::<_, PinnedMessage>(
+                r#"
+                SELECT p.message_id, m.message_from as sender_id,
+                COALESCE(m.content, jsonb_build_object('Text', jsonb_build_object('text', m.message))) as content,
+                m.created_at as time, p.pinned_by, p.created_at as pinned_at
+                FROM adv_chat.pinned_message p
+                JOIN adv_chat.private_message m ON m.message_id = p.message_id
+                WHERE NOT p.is_group AND p.user_a = $1 AND p.user_b = $2
+                AND (m.expires_at IS NULL OR m.expires_at > now() at time zone 'utc')
+                ORDER BY p.created_at
+                "#,
+            )
+            .bind(user_a)
+            .bind(user_b)
+            .fetch_all(&pool)
+            .await
+        }
+        MessageType::Group => {
+            match get_group_users(&group_info_table, &pool, conversation_id).await {
+                Ok(users) if users.contains(&user_id) => {}
+                Ok(_) => {
+                    return PinnedMessagesResult {
+                        state: PinMessageState::PermissionDenied,
+                        messages: None,
+                    }
+                    .into();
+                }
+                Err(e) => {
+                    debug!("{:?}", e);
+                    return PinnedMessagesResult {
+                        state: PinMessageState::OtherError,
+                        messages: None,
+                    }
+                    .into();
+                }
+            }
+            sqlx::query_as::<_, PinnedMessage>(
+                r#"
+                SELECT p.message_id, m.message_from as sender_id,
+                COALESCE(m.content, jsonb_build_object('Text', jsonb_build_object('text', m.group_message))) as content,
+                m.created_at as time, p.pinned_by, p.created_at as pinned_at
+                FROM adv_chat.pinned_message p
+                JOIN adv_chat.group_message m ON m.group_message_id = p.message_id
+                WHERE p.is_group AND p.group_id = $1
+                AND (m.expires_at IS NULL OR m.expires_at > now() at time zone 'utc')
+                ORDER BY p.created_at
+                "#,
+            )
+            .bind(conversation_id)
+            .fetch_all(&pool)
+            .await
+        }
+    };
+    match messages {
+        Ok(messages) => PinnedMessagesResult {
+            state: PinMessageState::Ok,
+            messages: Some(messages),
+        }
+        .into(),
+        Err(e) => {
+            debug!("{:?}", e);
+            PinnedMessagesResult {
+                state: PinMessageState::OtherError,
+                messages: None,
+            }
+            .into()
+        }
+    }
+}
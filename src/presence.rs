@@ -0,0 +1,492 @@
+use std::{collections::HashSet, time::Duration};
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::FromRow;
+use time::PrimitiveDateTime;
+use tracing::debug;
+
+use crate::{
+    event::{push_event_to_users, ServerEvent, Target},
+    friends::get_friend_ids,
+    helper::{get_user_id, now_utc, ConnectionPool, Session, SessionMap, UserConnectionMap},
+    replay::SEQ_SLOT_LOCK,
+};
+
+const SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// what a user chose to show while connected
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum ClientStatus {
+    Available,
+    Away,
+    DoNotDisturb,
+    /// connected, but shown as offline
+    Invisible,
+}
+
+impl ClientStatus {
+    fn as_str(self) -> &'static str {
+        match self {
+            ClientStatus::Available => "available",
+            ClientStatus::Away => "away",
+            ClientStatus::DoNotDisturb => "dnd",
+            ClientStatus::Invisible => "invisible",
+        }
+    }
+
+    fn parse(s: &str) -> Self {
+        match s {
+            "away" => ClientStatus::Away,
+            "dnd" => ClientStatus::DoNotDisturb,
+            "invisible" => ClientStatus::Invisible,
+            _ => ClientStatus::Available,
+        }
+    }
+}
+
+/// what friends see
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub enum PresenceStatus {
+    Online,
+    Away,
+    DoNotDisturb,
+    Offline,
+}
+
+fn visible_status(online: bool, status: ClientStatus) -> PresenceStatus {
+    match (online, status) {
+        (false, _) | (true, ClientStatus::Invisible) => PresenceStatus::Offline,
+        (true, ClientStatus::Available) => PresenceStatus::Online,
+        (true, ClientStatus::Away) => PresenceStatus::Away,
+        (true, ClientStatus::DoNotDisturb) => PresenceStatus::DoNotDisturb,
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct PresenceEvent {
+    user_id: u64,
+    status: PresenceStatus,
+    last_seen: Option<PrimitiveDateTime>,
+}
+
+#[derive(Debug, FromRow)]
+struct PresenceRow {
+    user_id: i64,
+    status: String,
+    last_seen: Option<PrimitiveDateTime>,
+}
+
+async fn get_presence_rows(
+    pool: &ConnectionPool,
+    user_ids: &[i64],
+) -> Result<Vec<PresenceRow>, sqlx::Error> {
+    sqlx::query_as::<_, PresenceRow>(
+        r#"
+        SELECT user_id, status, last_seen
+        FROM adv_chat.presence
+        WHERE user_id = ANY($1)
+        "#,
+    )
+    .bind(user_ids)
+    .fetch_all(pool)
+    .await
+}
+
+async fn get_client_status(
+    pool: &ConnectionPool,
+    user_id: i64,
+) -> Result<(ClientStatus, Option<PrimitiveDateTime>), sqlx::Error> {
+    let row = get_presence_rows(pool, &[user_id]).await?.pop();
+    Ok(match row {
+        Some(r) => (ClientStatus::parse(&r.status), r.last_seen),
+        None => (ClientStatus::Available, None),
+    })
+}
+
+async fn push_to_friends(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+    event: PresenceEvent,
+) {
+    let friend_ids = match get_friend_ids(pool, user_id as i64).await {
+        Ok(f) => f,
+        Err(_) => {
+            debug!("can't read friends of {}", user_id);
+            return;
+        }
+    };
+    let event = ServerEvent::Presence(event);
+    let recipients = friend_ids.into_iter().map(|f| (f as u64, Target::All));
+    push_event_to_users(user_connection_map, recipients, &event);
+}
+
+/// users of `user_ids` with a connection on any node
+pub async fn online_users(
+    pool: &ConnectionPool,
+    user_ids: &[i64],
+) -> Result<HashSet<u64>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (i64,)>(
+        r#"
+        SELECT DISTINCT user_id
+        FROM adv_chat.user_connection
+        WHERE user_id = ANY($1)
+        "#,
+    )
+    .bind(user_ids)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows.into_iter().map(|r| r.0 as u64).collect())
+}
+
+/// records whether this node still has connections of the user, returns the
+/// user's presence across the cluster when that changed
+async fn update_connection_row(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+) -> Result<Option<bool>, sqlx::Error> {
+    let mut tx = pool.begin().await?;
+    sqlx::query("SELECT pg_advisory_xact_lock(hashtext('presence:' || $1::text))")
+        .bind(user_id as i64)
+        .execute(&mut *tx)
+        .await?;
+    let slots =
+        sqlx::query_as::<_, (i32,)>("SELECT slot FROM adv_chat.user_connection WHERE user_id = $1")
+            .bind(user_id as i64)
+            .fetch_all(&mut *tx)
+            .await?;
+    // read under the lock, so of two changes close together the later one decides
+    let (slot, here) = {
+        let map = user_connection_map.lock().unwrap();
+        (map.slot() as i32, map.is_online(user_id))
+    };
+    let had = slots.iter().any(|s| s.0 == slot);
+    let elsewhere = slots.iter().any(|s| s.0 != slot);
+    if here && !had {
+        sqlx::query("INSERT INTO adv_chat.user_connection (user_id, slot) VALUES($1, $2)")
+            .bind(user_id as i64)
+            .bind(slot)
+            .execute(&mut *tx)
+            .await?;
+    } else if !here && had {
+        sqlx::query("DELETE FROM adv_chat.user_connection WHERE user_id = $1 AND slot = $2")
+            .bind(user_id as i64)
+            .bind(slot)
+            .execute(&mut *tx)
+            .await?;
+    }
+    tx.commit().await?;
+    let before = had || elsewhere;
+    let after = here || elsewhere;
+    Ok((before != after).then_some(after))
+}
+
+/// called when the first connection of a user on this node opens or the last
+/// one closes, friends are told once the user comes online or goes offline
+/// across all nodes
+pub async fn presence_changed(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+) {
+    match update_connection_row(pool, user_connection_map, user_id).await {
+        Ok(Some(online)) => announce(pool, user_connection_map, user_id, !online).await,
+        Ok(None) => {}
+        Err(e) => debug!("failed to update presence of {}: {:?}", user_id, e),
+    }
+}
+
+/// drops the rows of a slot whose node is gone, its users go offline unless
+/// connected elsewhere
+pub async fn forget_slot(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    slot: u64,
+) -> Result<(), sqlx::Error> {
+    let user_ids = sqlx::query_as::<_, (i64,)>(
+        "DELETE FROM adv_chat.user_connection WHERE slot = $1 RETURNING user_id",
+    )
+    .bind(slot as i32)
+    .fetch_all(pool)
+    .await?;
+    let user_ids: Vec<i64> = user_ids.into_iter().map(|u| u.0).collect();
+    let online = online_users(pool, &user_ids).await?;
+    for user_id in user_ids {
+        if !online.contains(&(user_id as u64)) {
+            announce(pool, user_connection_map, user_id as u64, true).await;
+        }
+    }
+    Ok(())
+}
+
+/// forgets connections of nodes that stopped without closing them
+pub async fn run_presence_sweeper(pool: ConnectionPool, user_connection_map: UserConnectionMap) {
+    let mut interval = tokio::time::interval(SWEEP_INTERVAL);
+    loop {
+        interval.tick().await;
+        if let Err(e) = sweep_dead_slots(&pool, &user_connection_map).await {
+            debug!("failed to sweep presence: {:?}", e);
+        }
+    }
+}
+
+async fn sweep_dead_slots(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+) -> Result<(), sqlx::Error> {
+    let slot = user_connection_map.lock().unwrap().slot();
+    let slots = sqlx::query_as::<_, (i32,)>(
+        "SELECT DISTINCT slot FROM adv_chat.user_connection WHERE slot <> $1",
+    )
+    .bind(slot as i32)
+    .fetch_all(pool)
+    .await?;
+    let mut conn = pool.acquire().await?;
+    for (slot,) in slots {
+        // a slot nobody holds belongs to a node that is gone
+        let (free,) = sqlx::query_as::<_, (bool,)>("SELECT pg_try_advisory_lock(hashtext($1), $2)")
+            .bind(SEQ_SLOT_LOCK)
+            .bind(slot)
+            .fetch_one(&mut *conn)
+            .await?;
+        if !free {
+            continue;
+        }
+        let forgotten = forget_slot(pool, user_connection_map, slot as u64).await;
+        sqlx::query("SELECT pg_advisory_unlock(hashtext($1), $2)")
+            .bind(SEQ_SLOT_LOCK)
+            .bind(slot)
+            .execute(&mut *conn)
+            .await?;
+        forgotten?;
+    }
+    Ok(())
+}
+
+async fn announce(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+    went_offline: bool,
+) {
+    let (status, mut last_seen) = match get_client_status(pool, user_id as i64).await {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("{:?}", e);
+            return;
+        }
+    };
+    // an invisible user's last seen stays where it was when they disappeared
+    if went_offline && status != ClientStatus::Invisible {
+        let now = now_utc();
+        let stored = sqlx::query(
+            r#"
+            INSERT INTO adv_chat.presence (user_id, status, last_seen)
+            VALUES($1, $2, $3)
+            ON CONFLICT (user_id) DO UPDATE SET last_seen = $3
+            "#,
+        )
+        .bind(user_id as i64)
+        .bind(status.as_str())
+        .bind(now)
+        .execute(pool)
+        .await;
+        if let Err(e) = stored {
+            debug!("failed to store last seen: {:?}", e);
+        }
+        last_seen = Some(now);
+    }
+    if status == ClientStatus::Invisible {
+        return;
+    }
+    let event = PresenceEvent {
+        user_id,
+        status: visible_status(!went_offline, status),
+        last_seen,
+    };
+    push_to_friends(pool, user_connection_map, user_id, event).await;
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SetStatusRequest {
+    session: Session,
+    status: ClientStatus,
+}
+
+#[derive(Debug, Serialize)]
+pub enum PresenceState {
+    Ok,
+    NotLogin,
+    OtherError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SetStatusResult {
+    state: PresenceState,
+}
+
+pub async fn set_presence_status(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(user_connection_map): State<UserConnectionMap>,
+    Json(status_req): Json<SetStatusRequest>,
+) -> Json<SetStatusResult> {
+    let user_id = match get_user_id(session_map, status_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return SetStatusResult {
+                state: PresenceState::NotLogin,
+            }
+            .into();
+        }
+    };
+    let (previous, last_seen) = match get_client_status(&pool, user_id as i64).await {
+        Ok(s) => s,
+        Err(e) => {
+            debug!("{:?}", e);
+            return SetStatusResult {
+                state: PresenceState::OtherError,
+            }
+            .into();
+        }
+    };
+    let stored = sqlx::query(
+        r#"
+        INSERT INTO adv_chat.presence (user_id, status)
+        VALUES($1, $2)
+        ON CONFLICT (user_id) DO UPDATE SET status = $2
+        "#,
+    )
+    .bind(user_id as i64)
+    .bind(status_req.status.as_str())
+    .execute(&pool)
+    .await;
+    if let Err(e) = stored {
+        debug!("failed to store presence status: {:?}", e);
+        return SetStatusResult {
+            state: PresenceState::OtherError,
+        }
+        .into();
+    }
+    let online = match online_users(&pool, &[user_id as i64]).await {
+        Ok(online) => !online.is_empty(),
+        Err(e) => {
+            debug!("{:?}", e);
+            user_connection_map.lock().unwrap().is_online(user_id)
+        }
+    };
+    let before = visible_status(online, previous);
+    let after = visible_status(online, status_req.status);
+    if before != after {
+        // going invisible looks like going offline, without a new last seen
+        let event = PresenceEvent {
+            user_id,
+            status: after,
+            last_seen,
+        };
+        push_to_friends(&pool, &user_connection_map, user_id, event).await;
+    }
+    SetStatusResult {
+        state: PresenceState::Ok,
+    }
+    .into()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct FriendsPresenceRequest {
+    session: Session,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FriendsPresenceResult {
+    state: PresenceState,
+    presence: Option<Vec<PresenceEvent>>,
+}
+
+pub async fn query_friends_presence(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(presence_req): Json<FriendsPresenceRequest>,
+) -> Json<FriendsPresenceResult> {
+    let user_id = match get_user_id(session_map, presence_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return FriendsPresenceResult {
+                state: PresenceState::NotLogin,
+                presence: None,
+            }
+            .into();
+        }
+    };
+    let friend_ids = match get_friend_ids(&pool, user_id as i64).await {
+        Ok(f) => f,
+        Err(_) => {
+            return FriendsPresenceResult {
+                state: PresenceState::OtherError,
+                presence: None,
+            }
+            .into();
+        }
+    };
+    let rows = match get_presence_rows(&pool, &friend_ids).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            debug!("{:?}", e);
+            return FriendsPresenceResult {
+                state: PresenceState::OtherError,
+                presence: None,
+            }
+            .into();
+        }
+    };
+    let online = match online_users(&pool, &friend_ids).await {
+        Ok(online) => online,
+        Err(e) => {
+            debug!("{:?}", e);
+            return FriendsPresenceResult {
+                state: PresenceState::OtherError,
+                presence: None,
+            }
+            .into();
+        }
+    };
+    let presence = friend_ids
+        .iter()
+        .map(|friend_id| {
+            let row = rows.iter().find(|r| r.user_id == *friend_id);
+            let status = row.map_or(ClientStatus::Available, |r| ClientStatus::parse(&r.status));
+            PresenceEvent {
+                user_id: *friend_id as u64,
+                status: visible_status(online.contains(&(*friend_id as u64)), status),
+                last_seen: row.and_then(|r| r.last_seen),
+            }
+        })
+        .collect();
+    FriendsPresenceResult {
+        state: PresenceState::Ok,
+        presence: Some(presence),
+    }
+    .into()
+}
+
+#[test]
+fn invisible_looks_offline() {
+    assert_eq!(
+        visible_status(true, ClientStatus::Invisible),
+        PresenceStatus::Offline
+    );
+    assert_eq!(
+        visible_status(false, ClientStatus::Away),
+        PresenceStatus::Offline
+    );
+    assert_eq!(
+        visible_status(true, ClientStatus::DoNotDisturb),
+        PresenceStatus::DoNotDisturb
+    );
+    assert_eq!(
+        ClientStatus::parse(ClientStatus::Invisible.as_str()),
+        ClientStatus::Invisible
+    );
+}
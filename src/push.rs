@@ -0,0 +1,573 @@
+use std::{
+    collections::{HashMap, HashSet},
+    num::NonZeroUsize,
+    sync::Arc,
+    time::Duration,
+};
+
+use axum::{extract::State, Json};
+use lru::LruCache;
+use serde::{Deserialize, Serialize};
+use time::{OffsetDateTime, UtcOffset};
+use tokio::{sync::mpsc, time::Instant};
+use tracing::debug;
+
+use crate::{
+    helper::{get_user_id, ConnectionPool, Session, SessionMap, UserConnectionMap},
+    message::{ChatMessage, Conversation},
+    notifier::{Device, Notifier, NotifyError, Platform, PushNotification},
+    presence::online_users,
+};
+
+/// capacity of the channel the dispatcher reports offline recipients through
+pub const PUSH_QUEUE: usize = 4096;
+/// messages arriving this long after the first one share its notification
+const COLLAPSE_WINDOW: Duration = Duration::from_secs(5);
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const PREVIEW_LENGTH: usize = 100;
+const MAX_TOKEN_LENGTH: usize = 512;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+const MAX_UTC_OFFSET_MINUTES: i16 = 14 * 60;
+/// one notification per user, a newer one replaces what the device shows
+const COLLAPSE_KEY: &str = "new_messages";
+/// offline users whose unread count is kept, the count of whoever was notified
+/// least recently starts over when more are
+const UNREAD_USERS: usize = 64 * 1024;
+
+/// a message for a user with no tunnel open on this node
+#[derive(Debug, Clone)]
+pub struct OfflineMessage {
+    pub user_id: u64,
+    pub conversation: Conversation,
+    pub preview: String,
+}
+
+impl OfflineMessage {
+    pub fn new(user_id: u64, message: &ChatMessage) -> Self {
+        OfflineMessage {
+            user_id,
+            conversation: Conversation::of(message),
+            preview: message
+                .content
+                .plain_text()
+                .chars()
+                .take(PREVIEW_LENGTH)
+                .collect(),
+        }
+    }
+}
+
+/// a daily span in the user's local time without notifications, it wraps past
+/// midnight when `end_minute` is before `start_minute`
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq)]
+pub struct QuietHours {
+    pub start_minute: u16,
+    pub end_minute: u16,
+    pub utc_offset_minutes: i16,
+}
+
+impl QuietHours {
+    fn is_valid(&self) -> bool {
+        self.start_minute < MINUTES_PER_DAY
+            && self.end_minute < MINUTES_PER_DAY
+            && self.utc_offset_minutes.abs() <= MAX_UTC_OFFSET_MINUTES
+    }
+
+    /// how long the quiet hours still last at `now`, `None` outside of them
+    pub fn remaining(&self, now: OffsetDateTime) -> Option<Duration> {
+        let offset = UtcOffset::from_whole_seconds(self.utc_offset_minutes as i32 * 60).ok()?;
+        let local = now.to_offset(offset);
+        let minute = local.hour() as u16 * 60 + local.minute() as u16;
+        let inside = if self.start_minute <= self.end_minute {
+            self.start_minute <= minute && minute < self.end_minute
+        } else {
+            minute >= self.start_minute || minute < self.end_minute
+        };
+        if !inside {
+            return None;
+        }
+        let minutes_left = (self.end_minute + MINUTES_PER_DAY - minute) % MINUTES_PER_DAY;
+        Some(Duration::from_secs(
+            minutes_left as u64 * 60 - local.second() as u64,
+        ))
+    }
+}
+
+/// messages for one user waiting to become a notification
+#[derive(Debug)]
+struct PendingPush {
+    due: Instant,
+    messages: u32,
+    conversations: HashSet<Conversation>,
+    latest: String,
+}
+
+impl PendingPush {
+    fn new(due: Instant) -> Self {
+        PendingPush {
+            due,
+            messages: 0,
+            conversations: HashSet::new(),
+            latest: String::new(),
+        }
+    }
+
+    fn add(&mut self, message: OfflineMessage) {
+        self.messages += 1;
+        self.conversations.insert(message.conversation);
+        self.latest = message.preview;
+    }
+
+    /// `unread` counts every message since the user was last online
+    fn notification(&self, unread: u32) -> PushNotification {
+        let (title, body) = match (self.messages, self.conversations.len()) {
+            (1, _) => ("New message".to_string(), self.latest.clone()),
+            (n, 1) => (format!("{} new messages", n), self.latest.clone()),
+            (n, c) => (format!("{} new messages", n), format!("in {} chats", c)),
+        };
+        PushNotification {
+            title,
+            body,
+            collapse_key: COLLAPSE_KEY.to_string(),
+            unread,
+        }
+    }
+}
+
+/// turns messages for offline users into push notifications, several messages
+/// arriving close together become one, and none are sent during quiet hours
+pub async fn run_push<N: Notifier>(
+    pool: ConnectionPool,
+    notifier: N,
+    user_connection_map: UserConnectionMap,
+    mut receiver: mpsc::Receiver<OfflineMessage>,
+) {
+    let notifier = Arc::new(notifier);
+    let mut pending: HashMap<u64, PendingPush> = HashMap::new();
+    let mut unread = LruCache::new(NonZeroUsize::new(UNREAD_USERS).unwrap());
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    loop {
+        tokio::select! {
+            message = receiver.recv() => match message {
+                Some(message) => pending
+                    .entry(message.user_id)
+                    .or_insert_with(|| PendingPush::new(Instant::now() + COLLAPSE_WINDOW))
+                    .add(message),
+                None => break,
+            },
+            _ = interval.tick() => {
+                flush(&pool, &notifier, &user_connection_map, &mut pending, &mut unread).await;
+            }
+        }
+    }
+}
+
+async fn flush<N: Notifier>(
+    pool: &ConnectionPool,
+    notifier: &Arc<N>,
+    user_connection_map: &UserConnectionMap,
+    pending: &mut HashMap<u64, PendingPush>,
+    unread: &mut LruCache<u64, u32>,
+) {
+    let now = Instant::now();
+    {
+        // whoever came back reads the messages in the app
+        let map = user_connection_map.lock().unwrap();
+        pending.retain(|user_id, _| !map.is_online(*user_id));
+        let back: Vec<u64> = unread
+            .iter()
+            .map(|(user_id, _)| *user_id)
+            .filter(|user_id| map.is_online(*user_id))
+            .collect();
+        for user_id in back {
+            unread.pop(&user_id);
+        }
+    }
+    let due: Vec<i64> = pending
+        .iter()
+        .filter(|(_, p)| p.due <= now)
+        .map(|(user_id, _)| *user_id as i64)
+        .collect();
+    if due.is_empty() {
+        return;
+    }
+    // or connected to another node
+    let online = match online_users(pool, &due).await {
+        Ok(online) => online,
+        Err(e) => {
+            debug!("failed to check presence: {:?}", e);
+            HashSet::new()
+        }
+    };
+    pending.retain(|user_id, _| !online.contains(user_id));
+    for user_id in &online {
+        unread.pop(user_id);
+    }
+    let due: Vec<i64> = due
+        .into_iter()
+        .filter(|user_id| !online.contains(&(*user_id as u64)))
+        .collect();
+    let quiet_hours = match load_quiet_hours(pool, &due).await {
+        Ok(q) => q,
+        Err(e) => {
+            debug!("failed to read quiet hours: {:?}", e);
+            HashMap::new()
+        }
+    };
+    let wall_clock = OffsetDateTime::now_utc();
+    let mut notifications = HashMap::new();
+    for user_id in due {
+        let user_id = user_id as u64;
+        let held = quiet_hours
+            .get(&user_id)
+            .and_then(|q| q.remaining(wall_clock));
+        if let Some(remaining) = held {
+            // what arrives meanwhile joins it, one notification when they end
+            pending.get_mut(&user_id).unwrap().due = now + remaining;
+            continue;
+        }
+        let push = pending.remove(&user_id).unwrap();
+        let count = unread.get_or_insert_mut(user_id, || 0);
+        *count += push.messages;
+        notifications.insert(user_id, push.notification(*count));
+    }
+    if notifications.is_empty() {
+        return;
+    }
+    let user_ids: Vec<i64> = notifications.keys().map(|u| *u as i64).collect();
+    let devices = match load_devices(pool, &user_ids).await {
+        Ok(d) => d,
+        Err(e) => {
+            debug!("failed to read push tokens: {:?}", e);
+            return;
+        }
+    };
+    let deliveries = devices
+        .into_iter()
+        .map(|(user_id, device)| (device, notifications[&user_id].clone()))
+        .collect();
+    let pool = pool.clone();
+    let notifier = notifier.clone();
+    tokio::spawn(async move {
+        let gone = send_to_devices(notifier.as_ref(), deliveries).await;
+        if gone.is_empty() {
+            return;
+        }
+        let deleted = sqlx::query("DELETE FROM adv_chat.push_token WHERE token = ANY($1)")
+            .bind(&gone)
+            .execute(&pool)
+            .await;
+        if let Err(e) = deleted {
+            debug!("failed to forget push tokens: {:?}", e);
+        }
+    });
+}
+
+/// returns the tokens the push services no longer accept
+async fn send_to_devices<N: Notifier>(
+    notifier: &N,
+    deliveries: Vec<(Device, PushNotification)>,
+) -> Vec<String> {
+    let mut gone = vec![];
+    for (device, notification) in deliveries {
+        match notifier.notify(&device, &notification).await {
+            Ok(()) => {}
+            Err(NotifyError::Gone) => gone.push(device.token),
+            Err(e) => debug!("failed to push to {}: {}", device.platform.as_str(), e),
+        }
+    }
+    gone
+}
+
+async fn load_quiet_hours(
+    pool: &ConnectionPool,
+    user_ids: &[i64],
+) -> Result<HashMap<u64, QuietHours>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (i64, i32, i32, i32)>(
+        r#"
+        SELECT user_id, quiet_start_minute, quiet_end_minute, utc_offset_minutes
+        FROM adv_chat.push_setting
+        WHERE user_id = ANY($1)
+        "#,
+    )
+    .bind(user_ids)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|(user_id, start, end, offset)| {
+            let quiet_hours = QuietHours {
+                start_minute: start as u16,
+                end_minute: end as u16,
+                utc_offset_minutes: offset as i16,
+            };
+            (user_id as u64, quiet_hours)
+        })
+        .collect())
+}
+
+async fn load_devices(
+    pool: &ConnectionPool,
+    user_ids: &[i64],
+) -> Result<Vec<(u64, Device)>, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (i64, String, String)>(
+        r#"
+        SELECT user_id, platform, token
+        FROM adv_chat.push_token
+        WHERE user_id = ANY($1)
+        "#,
+    )
+    .bind(user_ids)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .filter_map(|(user_id, platform, token)| {
+            let platform = Platform::parse(&platform)?;
+            Some((user_id as u64, Device { platform, token }))
+        })
+        .collect())
+}
+
+#[derive(Debug, Serialize)]
+pub enum PushState {
+    Ok,
+    NotLogin,
+    InvalidToken,
+    InvalidQuietHours,
+    OtherError,
+}
+
+#[derive(Debug, Serialize)]
+pub struct PushResult {
+    state: PushState,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct RegisterTokenRequest {
+    session: Session,
+    platform: Platform,
+    token: String,
+}
+
+pub async fn register_push_token(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(register_req): Json<RegisterTokenRequest>,
+) -> Json<PushResult> {
+    let user_id = match get_user_id(session_map, register_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return PushResult {
+                state: PushState::NotLogin,
+            }
+            .into()
+        }
+    };
+    let token = register_req.token.trim();
+    if token.is_empty() || token.len() > MAX_TOKEN_LENGTH {
+        return PushResult {
+            state: PushState::InvalidToken,
+        }
+        .into();
+    }
+    // a device that signs into another account moves over to it
+    let stored = sqlx::query(
+        r#"
+        INSERT INTO adv_chat.push_token (token, user_id, platform, created_at)
+        VALUES($1, $2, $3, now() at time zone 'utc')
+        ON CONFLICT (token) DO UPDATE
+        SET user_id = $2, platform = $3, created_at = now() at time zone 'utc'
+        "#,
+    )
+    .bind(token)
+    .bind(user_id as i64)
+    .bind(register_req.platform.as_str())
+    .execute(&pool)
+    .await;
+    match stored {
+        Ok(_) => PushResult {
+            state: PushState::Ok,
+        }
+        .into(),
+        Err(e) => {
+            debug!("failed to store push token: {:?}", e);
+            PushResult {
+                state: PushState::OtherError,
+            }
+            .into()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnregisterTokenRequest {
+    session: Session,
+    token: String,
+}
+
+pub async fn unregister_push_token(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(unregister_req): Json<UnregisterTokenRequest>,
+) -> Json<PushResult> {
+    let user_id = match get_user_id(session_map, unregister_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return PushResult {
+                state: PushState::NotLogin,
+            }
+            .into()
+        }
+    };
+    let deleted = sqlx::query(
+        r#"
+        DELETE FROM adv_chat.push_token
+        WHERE token = $1 AND user_id = $2
+        "#,
+    )
+    .bind(unregister_req.token.trim())
+    .bind(user_id as i64)
+    .execute(&pool)
+    .await;
+    match deleted {
+        Ok(_) => PushResult {
+            state: PushState::Ok,
+        }
+        .into(),
+        Err(e) => {
+            debug!("failed to delete push token: {:?}", e);
+            PushResult {
+                state: PushState::OtherError,
+            }
+            .into()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct QuietHoursRequest {
+    session: Session,
+    /// `None` turns quiet hours off
+    quiet_hours: Option<QuietHours>,
+}
+
+pub async fn set_quiet_hours(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(quiet_req): Json<QuietHoursRequest>,
+) -> Json<PushResult> {
+    let user_id = match get_user_id(session_map, quiet_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return PushResult {
+                state: PushState::NotLogin,
+            }
+            .into()
+        }
+    };
+    let stored = match quiet_req.quiet_hours {
+        Some(quiet_hours) if !quiet_hours.is_valid() => {
+            return PushResult {
+                state: PushState::InvalidQuietHours,
+            }
+            .into();
+        }
+        Some(quiet_hours) => {
+            sqlx::query(
+                r#"
+                INSERT INTO adv_chat.push_setting
+                (user_id, quiet_start_minute, quiet_end_minute, utc_offset_minutes)
+                VALUES($1, $2, $3, $4)
+                ON CONFLICT (user_id) DO UPDATE
+                SET quiet_start_minute = $2, quiet_end_minute = $3, utc_offset_minutes = $4
+                "#,
+            )
+            .bind(user_id as i64)
+            .bind(quiet_hours.start_minute as i32)
+            .bind(quiet_hours.end_minute as i32)
+            .bind(quiet_hours.utc_offset_minutes as i32)
+            .execute(&pool)
+            .await
+        }
+        None => {
+            sqlx::query("DELETE FROM adv_chat.push_setting WHERE user_id = $1")
+                .bind(user_id as i64)
+                .execute(&pool)
+                .await
+        }
+    };
+    match stored {
+        Ok(_) => PushResult {
+            state: PushState::Ok,
+        }
+        .into(),
+        Err(e) => {
+            debug!("failed to store quiet hours: {:?}", e);
+            PushResult {
+                state: PushState::OtherError,
+            }
+            .into()
+        }
+    }
+}
+
+#[test]
+fn quiet_hours_wrap_past_midnight() {
+    let at = |hour, minute, second| {
+        time::Date::from_calendar_date(2023, time::Month::May, 1)
+            .unwrap()
+            .with_hms(hour, minute, second)
+            .unwrap()
+            .assume_utc()
+    };
+    // 22:00 to 07:00 in utc+8
+    let quiet_hours = QuietHours {
+        start_minute: 22 * 60,
+        end_minute: 7 * 60,
+        utc_offset_minutes: 8 * 60,
+    };
+    assert!(quiet_hours.is_valid());
+    assert_eq!(
+        quiet_hours.remaining(at(15, 30, 0)),
+        Some(Duration::from_secs(7 * 3600 + 30 * 60))
+    );
+    assert_eq!(
+        quiet_hours.remaining(at(22, 59, 30)),
+        Some(Duration::from_secs(30))
+    );
+    assert_eq!(quiet_hours.remaining(at(23, 0, 0)), None);
+    assert_eq!(quiet_hours.remaining(at(13, 59, 0)), None);
+}
+
+#[tokio::test]
+async fn messages_collapse_into_one_notification() {
+    use crate::notifier::LogNotifier;
+
+    let message = |conversation, preview: &str| OfflineMessage {
+        user_id: 1,
+        conversation,
+        preview: preview.to_string(),
+    };
+    let mut push = PendingPush::new(Instant::now());
+    push.add(message(Conversation::private(1, 2), "hi"));
+    assert_eq!(push.notification(1).body, "hi");
+    push.add(message(Conversation::private(1, 2), "are you there"));
+    let notification = push.notification(2);
+    assert_eq!(notification.title, "2 new messages");
+    assert_eq!(notification.body, "are you there");
+    push.add(message(Conversation::Group(7), "lunch?"));
+    let notification = push.notification(5);
+    assert_eq!(notification.title, "3 new messages");
+    assert_eq!(notification.body, "in 2 chats");
+    assert_eq!(notification.unread, 5);
+
+    let notifier = LogNotifier::default();
+    let device = Device {
+        platform: Platform::Apns,
+        token: "abc".to_string(),
+    };
+    let gone = send_to_devices(&notifier, vec![(device.clone(), notification.clone())]).await;
+    assert!(gone.is_empty());
+    assert_eq!(notifier.sent(), vec![(device, notification)]);
+}
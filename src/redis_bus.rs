@@ -0,0 +1,219 @@
+use std::collections::VecDeque;
+
+use redis::{
+    aio::{Connection, MultiplexedConnection},
+    streams::{StreamMaxlen, StreamRangeReply, StreamReadOptions, StreamReadReply},
+    AsyncCommands, Client,
+};
+use tracing::debug;
+
+use crate::bus::{BusError, BusSubscriber, Envelope, EventBus};
+
+const STREAM: &str = "adv_chat:cluster";
+/// trimmed approximately, a subscriber further behind than this misses messages
+/// and is told so
+const MAX_STREAM_LEN: usize = 100_000;
+/// where reading starts from the oldest entry still kept
+const STREAM_START: &str = "0-0";
+const READ_BLOCK_MS: usize = 5000;
+const READ_COUNT: usize = 128;
+
+/// one redis stream every node appends to and reads from
+pub struct RedisBus {
+    client: Client,
+    connection: Option<MultiplexedConnection>,
+}
+
+impl RedisBus {
+    pub fn open(url: &str) -> Result<Self, BusError> {
+        Ok(RedisBus {
+            client: Client::open(url)?,
+            connection: None,
+        })
+    }
+
+    async fn connection(&mut self) -> Result<&mut MultiplexedConnection, BusError> {
+        if self.connection.is_none() {
+            self.connection = Some(self.client.get_multiplexed_tokio_connection().await?);
+        }
+        Ok(self.connection.as_mut().unwrap())
+    }
+}
+
+/// reads with its own connection, a blocking read would hold up publishing
+pub struct RedisSubscriber {
+    client: Client,
+    connection: Option<Connection>,
+    last_id: String,
+    pending: VecDeque<Envelope>,
+}
+
+impl EventBus for RedisBus {
+    type Subscriber = RedisSubscriber;
+
+    async fn publish(&mut self, envelope: &Envelope) -> Result<(), BusError> {
+        let payload = serde_json::to_string(envelope).unwrap();
+        let added: Result<String, _> = self
+            .connection()
+            .await?
+            .xadd_maxlen(
+                STREAM,
+                StreamMaxlen::Approx(MAX_STREAM_LEN),
+                "*",
+                &[("envelope", payload)],
+            )
+            .await;
+        if let Err(e) = added {
+            // reconnects on the next publish
+            self.connection = None;
+            return Err(e.into());
+        }
+        Ok(())
+    }
+
+    async fn subscribe(&mut self) -> Result<RedisSubscriber, BusError> {
+        let mut connection = self.client.get_async_connection().await?;
+        let newest: StreamRangeReply = connection.xrevrange_count(STREAM, "+", "-", 1).await?;
+        let last_id = match newest.ids.first() {
+            Some(id) => id.id.clone(),
+            None => STREAM_START.to_string(),
+        };
+        Ok(RedisSubscriber {
+            client: self.client.clone(),
+            connection: Some(connection),
+            last_id,
+            pending: VecDeque::new(),
+        })
+    }
+}
+
+impl BusSubscriber for RedisSubscriber {
+    async fn next(&mut self) -> Result<Option<Envelope>, BusError> {
+        loop {
+            if let Some(envelope) = self.pending.pop_front() {
+                return Ok(Some(envelope));
+            }
+            // after reconnecting reading goes on from `last_id`, nothing is lost
+            if self.connection.is_none() {
+                self.connection = Some(self.client.get_async_connection().await?);
+            }
+            let connection = self.connection.as_mut().unwrap();
+            // the last entry read was trimmed away, so may be some that weren't read
+            if self.last_id != STREAM_START {
+                let oldest: StreamRangeReply =
+                    match connection.xrange_count(STREAM, "-", "+", 1).await {
+                        Ok(oldest) => oldest,
+                        Err(e) => {
+                            self.connection = None;
+                            return Err(e.into());
+                        }
+                    };
+                let trimmed = match oldest.ids.first() {
+                    Some(entry) => stream_id(&entry.id) > stream_id(&self.last_id),
+                    None => true,
+                };
+                if trimmed {
+                    debug!("cluster entries after {} were trimmed", self.last_id);
+                    self.last_id = STREAM_START.to_string();
+                    return Ok(None);
+                }
+            }
+            let options = StreamReadOptions::default()
+                .block(READ_BLOCK_MS)
+                .count(READ_COUNT);
+            let reply: Option<StreamReadReply> = match connection
+                .xread_options(&[STREAM], &[&self.last_id], &options)
+                .await
+            {
+                Ok(reply) => reply,
+                Err(e) => {
+                    self.connection = None;
+                    return Err(e.into());
+                }
+            };
+            for key in reply.map(|r| r.keys).unwrap_or_default() {
+                for entry in key.ids {
+                    self.last_id = entry.id.clone();
+                    let payload: Option<String> = entry.get("envelope");
+                    match payload.map(|p| serde_json::from_str(&p)) {
+                        Some(Ok(envelope)) => self.pending.push_back(envelope),
+                        Some(Err(e)) => debug!("invalid cluster entry {}: {:?}", entry.id, e),
+                        None => debug!("cluster entry {} has no envelope", entry.id),
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// orders entry ids, which are `<milliseconds>-<sequence>`
+fn stream_id(id: &str) -> (u64, u64) {
+    let (millis, seq) = id.split_once('-').unwrap_or((id, "0"));
+    (millis.parse().unwrap_or(0), seq.parse().unwrap_or(0))
+}
+
+#[test]
+fn stream_ids_order_numerically() {
+    assert!(stream_id("1700000000000-0") > stream_id("999999999999-5"));
+    assert!(stream_id("1700000000000-10") > stream_id("1700000000000-9"));
+    assert_eq!(stream_id(STREAM_START), (0, 0));
+}
+
+/// `REDIS_URL=redis://127.0.0.1/ cargo test -- --ignored` with a redis-server running
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn redis_bus_delivers_in_order() {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let mut bus = RedisBus::open(&url).unwrap();
+    let mut subscriber = bus.subscribe().await.unwrap();
+    let node = uuid::Uuid::new_v4();
+    for group_id in 0..300 {
+        let envelope = Envelope {
+            node,
+            message: crate::cluster::ClusterMessage::GroupChanged { group_id },
+        };
+        bus.publish(&envelope).await.unwrap();
+    }
+    let mut expected = 0;
+    while expected < 300 {
+        let envelope = subscriber.next().await.unwrap().unwrap();
+        // other nodes may share the test server
+        if envelope.node != node {
+            continue;
+        }
+        match envelope.message {
+            crate::cluster::ClusterMessage::GroupChanged { group_id } => {
+                assert_eq!(group_id, expected)
+            }
+            other => panic!("unexpected {:?}", other),
+        }
+        expected += 1;
+    }
+}
+
+#[tokio::test]
+#[ignore = "needs a local redis-server"]
+async fn redis_bus_reports_trimmed_entries() {
+    let url = std::env::var("REDIS_URL").unwrap_or_else(|_| "redis://127.0.0.1/".to_string());
+    let mut bus = RedisBus::open(&url).unwrap();
+    let mut subscriber = bus.subscribe().await.unwrap();
+    let envelope = |group_id| Envelope {
+        node: uuid::Uuid::new_v4(),
+        message: crate::cluster::ClusterMessage::GroupChanged { group_id },
+    };
+    bus.publish(&envelope(0)).await.unwrap();
+    assert!(subscriber.next().await.unwrap().is_some());
+    for group_id in 1..4 {
+        bus.publish(&envelope(group_id)).await.unwrap();
+    }
+    let _: usize = bus
+        .connection()
+        .await
+        .unwrap()
+        .xtrim(STREAM, StreamMaxlen::Equals(1))
+        .await
+        .unwrap();
+    assert!(subscriber.next().await.unwrap().is_none());
+    // reading goes on from what is left
+    assert!(subscriber.next().await.unwrap().is_some());
+}
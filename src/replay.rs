@@ -0,0 +1,287 @@
+use std::time::Duration;
+
+use sqlx::{pool::PoolConnection, types::Json as SqlJson, Postgres};
+use tokio::sync::mpsc::Receiver;
+use tracing::debug;
+
+use crate::{
+    event::ServerEvent,
+    helper::{now_utc, ConnectionPool, UserConnectionMap},
+    tunnel::{Connection, ConnectionId, ServerFrame},
+};
+
+/// more missed events than this and the client has to sync from scratch
+const MAX_REPLAY: i64 = 1000;
+const RETENTION: &str = "3 days";
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+/// events waiting to be stored, one more only leaves a marker, see `Connections::store`
+pub const EVENT_QUEUE: usize = 64 * 1024;
+const WRITE_BATCH: usize = 500;
+/// nodes number events in steps of this, each from its own slot, so two nodes
+/// never hand out the same number
+pub const SEQ_SLOTS: u64 = 64;
+/// advisory lock a node holds on its slot, `pg_try_advisory_lock(hashtext(..), slot)`
+pub const SEQ_SLOT_LOCK: &str = "adv_chat.event_node";
+
+/// the numbering slot of this node, kept while its connection holds the lock
+pub struct SeqSlot {
+    pub slot: u64,
+    /// the newest number any node stored, new numbers start after it
+    pub last_seq: u64,
+    _lock: PoolConnection<Postgres>,
+}
+
+/// takes a slot no running node holds, `None` when all of them are taken
+pub async fn claim_seq_slot(pool: &ConnectionPool) -> Result<Option<SeqSlot>, sqlx::Error> {
+    let mut lock = pool.acquire().await?;
+    for slot in 0..SEQ_SLOTS {
+        let (claimed,) =
+            sqlx::query_as::<_, (bool,)>("SELECT pg_try_advisory_lock(hashtext($1), $2)")
+                .bind(SEQ_SLOT_LOCK)
+                .bind(slot as i32)
+                .fetch_one(&mut *lock)
+                .await?;
+        if claimed {
+            let (last_seq,) = sqlx::query_as::<_, (i64,)>(
+                "SELECT COALESCE(max(last_seq), 0) FROM adv_chat.event_node",
+            )
+            .fetch_one(&mut *lock)
+            .await?;
+            return Ok(Some(SeqSlot {
+                slot,
+                last_seq: last_seq as u64,
+                _lock: lock,
+            }));
+        }
+    }
+    Ok(None)
+}
+
+#[derive(Debug)]
+pub struct LoggedEvent {
+    pub user_id: u64,
+    pub seq: u64,
+    /// `None` marks an event that was lost before it was stored
+    pub event: Option<ServerEvent>,
+}
+
+/// stores every durable event so tunnels can resume after the in-memory log is gone
+pub async fn run_event_writer(
+    pool: ConnectionPool,
+    slot: u64,
+    mut receiver: Receiver<LoggedEvent>,
+) {
+    let mut prune = tokio::time::interval(PRUNE_INTERVAL);
+    loop {
+        tokio::select! {
+            logged = receiver.recv() => match logged {
+                Some(logged) => {
+                    // a message to a large group logs one event per member
+                    let mut batch = vec![logged];
+                    while batch.len() < WRITE_BATCH {
+                        match receiver.try_recv() {
+                            Ok(logged) => batch.push(logged),
+                            Err(_) => break,
+                        }
+                    }
+                    if let Err(e) = store_events(&pool, slot, &batch).await {
+                        debug!("failed to store events: {:?}", e);
+                    }
+                }
+                None => break,
+            },
+            _ = prune.tick() => {
+                if let Err(e) = prune_events(&pool, slot).await {
+                    debug!("failed to prune events: {:?}", e);
+                }
+            }
+        }
+    }
+}
+
+async fn store_events(
+    pool: &ConnectionPool,
+    slot: u64,
+    batch: &[LoggedEvent],
+) -> Result<(), sqlx::Error> {
+    let user_ids: Vec<i64> = batch.iter().map(|l| l.user_id as i64).collect();
+    let seqs: Vec<i64> = batch.iter().map(|l| l.seq as i64).collect();
+    let events: Vec<Option<String>> = batch
+        .iter()
+        .map(|l| l.event.as_ref().map(|e| serde_json::to_string(e).unwrap()))
+        .collect();
+    let mut tx = pool.begin().await?;
+    // numbers are unique per node, a conflict is the same event stored twice
+    let stored = sqlx::query(
+        r#"
+        INSERT INTO adv_chat.user_event
+        (user_id, seq, event, created_at)
+        SELECT user_id, seq, event::jsonb, now() at time zone 'utc'
+        FROM UNNEST($1::bigint[], $2::bigint[], $3::text[]) AS e(user_id, seq, event)
+        ON CONFLICT (user_id, seq) DO NOTHING
+        "#,
+    )
+    .bind(user_ids)
+    .bind(&seqs)
+    .bind(events)
+    .execute(&mut *tx)
+    .await?;
+    if stored.rows_affected() < batch.len() as u64 {
+        debug!(
+            "{} events were already stored",
+            batch.len() as u64 - stored.rows_affected()
+        );
+    }
+    sqlx::query(
+        r#"
+        INSERT INTO adv_chat.event_node
+        (slot, last_seq)
+        VALUES($1, $2)
+        ON CONFLICT (slot) DO UPDATE SET last_seq = GREATEST(event_node.last_seq, EXCLUDED.last_seq)
+        "#,
+    )
+    .bind(slot as i32)
+    .bind(seqs.iter().max())
+    .execute(&mut *tx)
+    .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
+/// drops old events and remembers the newest number dropped, every event
+/// after it is still stored
+async fn prune_events(pool: &ConnectionPool, slot: u64) -> Result<(), sqlx::Error> {
+    sqlx::query(
+        r#"
+        WITH pruned AS (
+            DELETE FROM adv_chat.user_event
+            WHERE created_at < now() at time zone 'utc' - $1::interval
+            RETURNING seq
+        )
+        INSERT INTO adv_chat.event_node
+        (slot, last_seq, pruned_seq)
+        SELECT $2, 0, max(seq) FROM pruned HAVING count(*) > 0
+        ON CONFLICT (slot) DO UPDATE SET pruned_seq = GREATEST(event_node.pruned_seq, EXCLUDED.pruned_seq)
+        "#,
+    )
+    .bind(RETENTION)
+    .bind(slot as i32)
+    .execute(pool)
+    .await?;
+    Ok(())
+}
+
+/// stored events after `seq`, `None` if some may have been pruned, one of them
+/// was lost, too much was missed, or `seq` is newer than any number handed out.
+/// `newest` is the newest number this node handed out
+async fn load_events_after(
+    pool: &ConnectionPool,
+    user_id: u64,
+    seq: u64,
+    newest: u64,
+) -> Result<Option<Vec<(u64, ServerEvent)>>, sqlx::Error> {
+    let (stored, pruned) = sqlx::query_as::<_, (i64, i64)>(
+        r#"
+        SELECT COALESCE(max(last_seq), 0), COALESCE(max(pruned_seq), 0)
+        FROM adv_chat.event_node
+        "#,
+    )
+    .fetch_one(pool)
+    .await?;
+    // `seq` needn't be an event of the user, a welcome gives out the newest
+    // number even when there was none
+    if seq < pruned as u64 || seq > newest.max(stored as u64) {
+        return Ok(None);
+    }
+    let rows = sqlx::query_as::<_, (i64, Option<SqlJson<ServerEvent>>)>(
+        r#"
+        SELECT seq, event
+        FROM adv_chat.user_event
+        WHERE user_id = $1 AND seq > $2
+        ORDER BY seq
+        LIMIT $3
+        "#,
+    )
+    .bind(user_id as i64)
+    .bind(seq as i64)
+    .bind(MAX_REPLAY + 1)
+    .fetch_all(pool)
+    .await?;
+    if rows.len() as i64 > MAX_REPLAY {
+        return Ok(None);
+    }
+    Ok(rows
+        .into_iter()
+        .map(|(seq, event)| event.map(|e| (seq as u64, e.0)))
+        .collect())
+}
+
+/// registers a new connection, first queueing whatever it missed since `last_seq`,
+/// or a resync request when that can no longer be replayed. returns whether the
+/// user just came online
+pub async fn resume(
+    pool: &ConnectionPool,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+    connection_id: ConnectionId,
+    connection: Connection,
+    last_seq: Option<u64>,
+) -> bool {
+    let (in_memory, newest) = {
+        let mut map = user_connection_map.lock().unwrap();
+        let in_memory = last_seq.and_then(|seq| map.events_after(user_id, seq));
+        (in_memory, map.newest_seq())
+    };
+    let stored = match (last_seq, &in_memory) {
+        (Some(seq), None) => match load_events_after(pool, user_id, seq, newest).await {
+            Ok(stored) => stored,
+            Err(e) => {
+                debug!("failed to load events: {:?}", e);
+                None
+            }
+        },
+        _ => None,
+    };
+    let mut map = user_connection_map.lock().unwrap();
+    let replay = match (last_seq, stored) {
+        (None, _) => Some(vec![]),
+        // the writer hasn't marked an event it lost yet
+        (Some(seq), Some(_)) if map.unstored_after(user_id, seq) => None,
+        (Some(seq), Some(mut stored)) => {
+            // events logged while loading, or not written yet
+            let newest = stored.last().map_or(seq, |e| e.0);
+            let recent = map.logged_after(user_id, newest);
+            stored.extend(recent);
+            Some(stored)
+        }
+        // looked up again under the lock, nothing may slip in before registering
+        (Some(seq), None) if in_memory.is_some() => map.events_after(user_id, seq),
+        (Some(_), None) => None,
+    };
+    let send = |frame| {
+        if !connection.sender.push(frame) {
+            debug!("outbound queue of connection {} overflowed", connection_id);
+        }
+    };
+    match replay {
+        Some(events) => {
+            let now = now_utc();
+            for (seq, event) in events {
+                let event = match event.unexpired(now) {
+                    Some(event) => event,
+                    None => continue,
+                };
+                send(ServerFrame::Event {
+                    seq: Some(seq),
+                    event,
+                });
+            }
+        }
+        None => send(ServerFrame::ResyncRequired),
+    }
+    send(ServerFrame::Welcome {
+        connection_id,
+        last_seq: map.last_seq(user_id),
+    });
+    map.register(user_id, connection_id, connection)
+}
@@ -0,0 +1,464 @@
+use std::{
+    cmp::Reverse,
+    collections::BinaryHeap,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlJson, FromRow};
+use time::PrimitiveDateTime;
+use tokio::sync::Notify;
+use tracing::{debug, info};
+
+use crate::{
+    helper::{
+        get_user_id, now_utc, ConnectionPool, GroupInfoTable, MessageSender, Session, SessionMap,
+    },
+    message::{check_receiver, ChatMessage, MessageType, SendRejection},
+    message_content::{ContentError, MessageContent},
+    outbox::{dispatch, record_with_entry},
+};
+
+const MAX_SCHEDULED_PER_USER: i64 = 100;
+const MAX_SCHEDULE_AHEAD_DAYS: i64 = 365;
+/// a message that failed to go out stays stored and is tried again this much later
+const RETRY_DELAY: time::Duration = time::Duration::seconds(10);
+
+type DueQueue = BinaryHeap<Reverse<(PrimitiveDateTime, i64)>>;
+
+/// due times of pending scheduled messages, the rows in
+/// `adv_chat.scheduled_message` stay the source of truth
+#[derive(Clone, Default)]
+pub struct Scheduler {
+    queue: Arc<Mutex<DueQueue>>,
+    notify: Arc<Notify>,
+}
+
+impl Scheduler {
+    pub fn schedule(&self, scheduled_id: i64, send_at: PrimitiveDateTime) {
+        self.queue
+            .lock()
+            .unwrap()
+            .push(Reverse((send_at, scheduled_id)));
+        self.notify.notify_one();
+    }
+
+    fn next_due(&self) -> Option<(PrimitiveDateTime, i64)> {
+        self.queue.lock().unwrap().peek().map(|r| r.0)
+    }
+
+    fn pop(&self) -> Option<(PrimitiveDateTime, i64)> {
+        self.queue.lock().unwrap().pop().map(|r| r.0)
+    }
+}
+
+/// loads the pending rows and dispatches them when they are due, runs forever
+pub async fn run_scheduler(
+    pool: ConnectionPool,
+    group_info_table: GroupInfoTable,
+    message_sender: MessageSender,
+    scheduler: Scheduler,
+) {
+    match sqlx::query_as::<_, (i64, PrimitiveDateTime)>(
+        r#"
+        SELECT scheduled_id, send_at
+        FROM adv_chat.scheduled_message
+        "#,
+    )
+    .fetch_all(&pool)
+    .await
+    {
+        Ok(rows) => {
+            info!("loaded {} scheduled messages", rows.len());
+            for (scheduled_id, send_at) in rows {
+                scheduler.schedule(scheduled_id, send_at);
+            }
+        }
+        Err(e) => debug!("failed to load scheduled messages: {:?}", e),
+    }
+    loop {
+        match scheduler.next_due() {
+            None => scheduler.notify.notified().await,
+            Some((send_at, _)) => {
+                let wait = send_at - now_utc();
+                if wait.is_positive() {
+                    let wait = Duration::try_from(wait).unwrap_or(Duration::ZERO);
+                    tokio::select! {
+                        _ = tokio::time::sleep(wait) => {}
+                        _ = scheduler.notify.notified() => {}
+                    }
+                    continue;
+                }
+                if let Some((_, scheduled_id)) = scheduler.pop() {
+                    if let Err(e) =
+                        dispatch_scheduled(&pool, &group_info_table, &message_sender, scheduled_id)
+                            .await
+                    {
+                        debug!("failed to dispatch scheduled message: {:?}", e);
+                        scheduler.schedule(scheduled_id, now_utc() + RETRY_DELAY);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug, FromRow)]
+struct ScheduledRow {
+    is_group: bool,
+    sender_id: i64,
+    receiver_id: i64,
+    content: SqlJson<MessageContent>,
+}
+
+async fn dispatch_scheduled(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    message_sender: &MessageSender,
+    scheduled_id: i64,
+) -> Result<(), sqlx::Error> {
+    let now = now_utc();
+    // the row is only deleted together with storing the message
+    let mut tx = pool.begin().await?;
+    // the row is gone if it was cancelled, or has a later send_at if it was edited
+    let row = sqlx::query_as::<_, ScheduledRow>(
+        r#"
+        DELETE FROM adv_chat.scheduled_message
+        WHERE scheduled_id = $1 AND send_at <= $2
+        RETURNING is_group, sender_id, receiver_id, content
+        "#,
+    )
+    .bind(scheduled_id)
+    .bind(now)
+    .fetch_optional(&mut *tx)
+    .await?;
+    let row = match row {
+        Some(r) => r,
+        None => return Ok(()),
+    };
+    let message = ChatMessage {
+        message_type: if row.is_group {
+            MessageType::Group
+        } else {
+            MessageType::Private
+        },
+        message_id: None,
+        content: row.content.0,
+        sender_id: row.sender_id as u64,
+        receiver_id: row.receiver_id as u64,
+        time: now,
+        expires_at: None,
+        origin: None,
+    };
+    // membership may have changed since the message was scheduled
+    if let Err(e) = check_receiver(
+        pool,
+        group_info_table,
+        message.sender_id,
+        message.message_type,
+        message.receiver_id,
+    )
+    .await
+    {
+        debug!("dropped scheduled message {}: {:?}", scheduled_id, e);
+        tx.commit().await?;
+        return Ok(());
+    }
+    let entry = record_with_entry(&mut tx, message).await?;
+    tx.commit().await?;
+    dispatch(message_sender, entry).await;
+    Ok(())
+}
+
+#[derive(Debug, Serialize)]
+pub enum ScheduleMessageState {
+    Ok,
+    NotLogin,
+    InvalidContent(ContentError),
+    Rejected(SendRejection),
+    InvalidTime,
+    TooManyScheduled,
+    NotFound,
+    OtherError,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduleMessageRequest {
+    session: Session,
+    message_type: MessageType,
+    content: MessageContent,
+    receiver_id: u64,
+    send_at: PrimitiveDateTime,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduleMessageResult {
+    state: ScheduleMessageState,
+    scheduled_id: Option<u64>,
+}
+
+fn check_send_at(send_at: PrimitiveDateTime) -> bool {
+    let now = now_utc();
+    send_at > now && send_at - now <= time::Duration::days(MAX_SCHEDULE_AHEAD_DAYS)
+}
+
+pub async fn schedule_message(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(scheduler): State<Scheduler>,
+    State(group_info_table): State<GroupInfoTable>,
+    Json(schedule_req): Json<ScheduleMessageRequest>,
+) -> Json<ScheduleMessageResult> {
+    let result = |state| ScheduleMessageResult {
+        state,
+        scheduled_id: None,
+    };
+    let user_id = match get_user_id(session_map, schedule_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => return result(ScheduleMessageState::NotLogin).into(),
+    };
+    if let Err(e) = schedule_req.content.validate() {
+        return result(ScheduleMessageState::InvalidContent(e)).into();
+    }
+    if !check_send_at(schedule_req.send_at) {
+        return result(ScheduleMessageState::InvalidTime).into();
+    }
+    if let Err(e) = check_receiver(
+        &pool,
+        &group_info_table,
+        user_id as u64,
+        schedule_req.message_type,
+        schedule_req.receiver_id,
+    )
+    .await
+    {
+        return result(ScheduleMessageState::Rejected(e)).into();
+    }
+    let pending = sqlx::query_as::<_, (i64,)>(
+        r#"
+        SELECT COUNT(*)
+        FROM adv_chat.scheduled_message
+        WHERE sender_id = $1
+        "#,
+    )
+    .bind(user_id)
+    .fetch_one(&pool)
+    .await;
+    match pending {
+        Ok((count,)) if count >= MAX_SCHEDULED_PER_USER => {
+            return result(ScheduleMessageState::TooManyScheduled).into();
+        }
+        Ok(_) => {}
+        Err(e) => {
+            debug!("{:?}", e);
+            return result(ScheduleMessageState::OtherError).into();
+        }
+    }
+    let scheduled_id = sqlx::query_as::<_, (i64,)>(
+        r#"
+        INSERT INTO adv_chat.scheduled_message
+        (is_group, sender_id, receiver_id, content, send_at, created_at)
+        VALUES($1, $2, $3, $4, $5, now() at time zone 'utc')
+        RETURNING scheduled_id
+        "#,
+    )
+    .bind(matches!(schedule_req.message_type, MessageType::Group))
+    .bind(user_id)
+    .bind(schedule_req.receiver_id as i64)
+    .bind(SqlJson(&schedule_req.content))
+    .bind(schedule_req.send_at)
+    .fetch_one(&pool)
+    .await;
+    match scheduled_id {
+        Ok((scheduled_id,)) => {
+            scheduler.schedule(scheduled_id, schedule_req.send_at);
+            ScheduleMessageResult {
+                state: ScheduleMessageState::Ok,
+                scheduled_id: Some(scheduled_id as u64),
+            }
+            .into()
+        }
+        Err(e) => {
+            debug!("{:?}", e);
+            result(ScheduleMessageState::OtherError).into()
+        }
+    }
+}
+
+#[derive(Debug, Serialize, FromRow)]
+pub struct ScheduledMessage {
+    scheduled_id: i64,
+    is_group: bool,
+    receiver_id: i64,
+    content: SqlJson<MessageContent>,
+    send_at: PrimitiveDateTime,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct ScheduledMessagesRequest {
+    session: Session,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduledMessagesResult {
+    state: ScheduleMessageState,
+    messages: Option<Vec<ScheduledMessage>>,
+}
+
+pub async fn query_scheduled_messages(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(scheduled_req): Json<ScheduledMessagesRequest>,
+) -> Json<ScheduledMessagesResult> {
+    let user_id = match get_user_id(session_map, scheduled_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => {
+            return ScheduledMessagesResult {
+                state: ScheduleMessageState::NotLogin,
+                messages: None,
+            }
+            .into();
+        }
+    };
+    let messages = sqlx::query_as::<_, ScheduledMessage>(
+        r#"
+        SELECT scheduled_id, is_group, receiver_id, content, send_at
+        FROM adv_chat.scheduled_message
+        WHERE sender_id = $1
+        ORDER BY send_at
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&pool)
+    .await;
+    match messages {
+        Ok(messages) => ScheduledMessagesResult {
+            state: ScheduleMessageState::Ok,
+            messages: Some(messages),
+        }
+        .into(),
+        Err(e) => {
+            debug!("{:?}", e);
+            ScheduledMessagesResult {
+                state: ScheduleMessageState::OtherError,
+                messages: None,
+            }
+            .into()
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CancelScheduledRequest {
+    session: Session,
+    scheduled_id: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ScheduledUpdateResult {
+    state: ScheduleMessageState,
+}
+
+pub async fn cancel_scheduled_message(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(cancel_req): Json<CancelScheduledRequest>,
+) -> Json<ScheduledUpdateResult> {
+    let user_id = match get_user_id(session_map, cancel_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => {
+            return ScheduledUpdateResult {
+                state: ScheduleMessageState::NotLogin,
+            }
+            .into();
+        }
+    };
+    let deleted = sqlx::query(
+        r#"
+        DELETE FROM adv_chat.scheduled_message
+        WHERE scheduled_id = $1 AND sender_id = $2
+        "#,
+    )
+    .bind(cancel_req.scheduled_id as i64)
+    .bind(user_id)
+    .execute(&pool)
+    .await;
+    let state = match deleted {
+        Ok(r) if r.rows_affected() == 0 => ScheduleMessageState::NotFound,
+        Ok(_) => ScheduleMessageState::Ok,
+        Err(e) => {
+            debug!("{:?}", e);
+            ScheduleMessageState::OtherError
+        }
+    };
+    ScheduledUpdateResult { state }.into()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct EditScheduledRequest {
+    session: Session,
+    scheduled_id: u64,
+    content: Option<MessageContent>,
+    send_at: Option<PrimitiveDateTime>,
+}
+
+pub async fn edit_scheduled_message(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    State(scheduler): State<Scheduler>,
+    Json(edit_req): Json<EditScheduledRequest>,
+) -> Json<ScheduledUpdateResult> {
+    let user_id = match get_user_id(session_map, edit_req.session).await {
+        Some(user_id) => user_id as i64,
+        None => {
+            return ScheduledUpdateResult {
+                state: ScheduleMessageState::NotLogin,
+            }
+            .into();
+        }
+    };
+    if let Some(content) = &edit_req.content {
+        if let Err(e) = content.validate() {
+            return ScheduledUpdateResult {
+                state: ScheduleMessageState::InvalidContent(e),
+            }
+            .into();
+        }
+    }
+    if let Some(send_at) = edit_req.send_at {
+        if !check_send_at(send_at) {
+            return ScheduledUpdateResult {
+                state: ScheduleMessageState::InvalidTime,
+            }
+            .into();
+        }
+    }
+    let updated = sqlx::query_as::<_, (PrimitiveDateTime,)>(
+        r#"
+        UPDATE adv_chat.scheduled_message
+        SET content = COALESCE($3, content), send_at = COALESCE($4, send_at)
+        WHERE scheduled_id = $1 AND sender_id = $2
+        RETURNING send_at
+        "#,
+    )
+    .bind(edit_req.scheduled_id as i64)
+    .bind(user_id)
+    .bind(edit_req.content.as_ref().map(SqlJson))
+    .bind(edit_req.send_at)
+    .fetch_optional(&pool)
+    .await;
+    let state = match updated {
+        Ok(Some((send_at,))) => {
+            scheduler.schedule(edit_req.scheduled_id as i64, send_at);
+            ScheduleMessageState::Ok
+        }
+        Ok(None) => ScheduleMessageState::NotFound,
+        Err(e) => {
+            debug!("{:?}", e);
+            ScheduleMessageState::OtherError
+        }
+    };
+    ScheduledUpdateResult { state }.into()
+}
@@ -0,0 +1,554 @@
+use axum::{extract::State, Json};
+use serde::{Deserialize, Serialize};
+use sqlx::{types::Json as SqlJson, FromRow};
+use time::PrimitiveDateTime;
+use tracing::debug;
+
+use crate::{
+    helper::{get_user_id, ConnectionPool, OperationState, Session, SessionMap},
+    message::MessageType,
+    message_content::MessageContent,
+    user_info::get_user_group_ids,
+};
+
+const DEFAULT_SEARCH_LIMIT: u64 = 20;
+const MAX_SEARCH_LIMIT: u64 = 100;
+
+pub trait Tokenizer {
+    /// tokens written to the search index of a message
+    fn index_tokens(&self, text: &str) -> Vec<String>;
+    /// tokens which all have to be present in a matching message
+    fn query_tokens(&self, text: &str) -> Vec<String>;
+}
+
+/// splits latin text into lowercased words and CJK text into overlapping bigrams,
+/// CJK characters are indexed as unigrams too so one character queries still match
+pub struct NgramTokenizer;
+
+pub static TOKENIZER: NgramTokenizer = NgramTokenizer;
+
+fn is_cjk(c: char) -> bool {
+    matches!(c as u32,
+        0x3040..=0x30FF
+        | 0x3400..=0x4DBF
+        | 0x4E00..=0x9FFF
+        | 0xAC00..=0xD7AF
+        | 0xF900..=0xFAFF
+        | 0x20000..=0x2FFFF)
+}
+
+enum Run {
+    Word(String),
+    Cjk(Vec<char>),
+}
+
+/// a run and the char range of the original text it was read from, lowercasing
+/// may change the length of a word
+struct Span {
+    start: usize,
+    end: usize,
+    run: Run,
+}
+
+fn split_runs(text: &str) -> Vec<Span> {
+    let mut spans = vec![];
+    let mut word = String::new();
+    let mut cjk = vec![];
+    let mut start = 0;
+    for (i, c) in text.chars().enumerate() {
+        if is_cjk(c) {
+            if !word.is_empty() {
+                let run = Run::Word(std::mem::take(&mut word));
+                spans.push(Span { start, end: i, run });
+            }
+            if cjk.is_empty() {
+                start = i;
+            }
+            cjk.push(c);
+        } else if c.is_alphanumeric() {
+            if !cjk.is_empty() {
+                let run = Run::Cjk(std::mem::take(&mut cjk));
+                spans.push(Span { start, end: i, run });
+            }
+            if word.is_empty() {
+                start = i;
+            }
+            word.extend(c.to_lowercase());
+        } else {
+            if !word.is_empty() {
+                let run = Run::Word(std::mem::take(&mut word));
+                spans.push(Span { start, end: i, run });
+            }
+            if !cjk.is_empty() {
+                let run = Run::Cjk(std::mem::take(&mut cjk));
+                spans.push(Span { start, end: i, run });
+            }
+        }
+    }
+    let end = text.chars().count();
+    if !word.is_empty() {
+        spans.push(Span {
+            start,
+            end,
+            run: Run::Word(word),
+        });
+    }
+    if !cjk.is_empty() {
+        spans.push(Span {
+            start,
+            end,
+            run: Run::Cjk(cjk),
+        });
+    }
+    spans
+}
+
+impl Tokenizer for NgramTokenizer {
+    fn index_tokens(&self, text: &str) -> Vec<String> {
+        let mut tokens = vec![];
+        for span in split_runs(text) {
+            match span.run {
+                Run::Word(w) => tokens.push(w),
+                Run::Cjk(chars) => {
+                    tokens.extend(chars.iter().map(|c| c.to_string()));
+                    tokens.extend(chars.windows(2).map(|w| w.iter().collect()));
+                }
+            }
+        }
+        tokens
+    }
+
+    fn query_tokens(&self, text: &str) -> Vec<String> {
+        let mut tokens = vec![];
+        for span in split_runs(text) {
+            match span.run {
+                Run::Word(w) => tokens.push(w),
+                Run::Cjk(chars) if chars.len() == 1 => tokens.push(chars[0].to_string()),
+                Run::Cjk(chars) => tokens.extend(chars.windows(2).map(|w| w.iter().collect())),
+            }
+        }
+        tokens
+    }
+}
+
+/// text passed to `to_tsvector('simple', ..)` when a message is stored
+pub fn search_document(text: &str) -> String {
+    TOKENIZER.index_tokens(text).join(" ")
+}
+
+/// char ranges of `text` holding one of the tokens `query` is searched with
+pub fn highlight(text: &str, query: &str) -> Vec<(usize, usize)> {
+    let tokens = TOKENIZER.query_tokens(query);
+    let mut ranges: Vec<(usize, usize)> = vec![];
+    for span in split_runs(text) {
+        match &span.run {
+            Run::Word(w) => {
+                if tokens.contains(w) {
+                    ranges.push((span.start, span.end));
+                }
+            }
+            Run::Cjk(chars) => {
+                for token in &tokens {
+                    let token: Vec<char> = token.chars().collect();
+                    if token.len() > chars.len() {
+                        continue;
+                    }
+                    for (i, window) in chars.windows(token.len()).enumerate() {
+                        if window == &token[..] {
+                            ranges.push((span.start + i, span.start + i + token.len()));
+                        }
+                    }
+                }
+            }
+        }
+    }
+    ranges.sort();
+    let mut merged: Vec<(usize, usize)> = vec![];
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+const BACKFILL_BATCH: i64 = 500;
+
+/// fills `search_vector` of messages stored before it existed, a batch at a time
+pub async fn backfill_search_index(pool: ConnectionPool) {
+    let tables = [
+        ("private_message", "message_id", "message"),
+        ("group_message", "group_message_id", "group_message"),
+    ];
+    for (table, id_column, text_column) in tables {
+        loop {
+            match backfill_batch(&pool, table, id_column, text_column).await {
+                Ok(0) => break,
+                Ok(n) => debug!("indexed {} old rows of {}", n, table),
+                Err(e) => {
+                    debug!("failed to backfill the search index of {}: {:?}", table, e);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+async fn backfill_batch(
+    pool: &ConnectionPool,
+    table: &str,
+    id_column: &str,
+    text_column: &str,
+) -> Result<usize, sqlx::Error> {
+    let rows = sqlx::query_as::<_, (i64, Option<String>)>(&format!(
+        r#"
+        SELECT {id}, {text}
+        FROM adv_chat.{table}
+        WHERE search_vector IS NULL
+        ORDER BY {id}
+        LIMIT $1
+        "#,
+        id = id_column,
+        text = text_column,
+        table = table,
+    ))
+    .bind(BACKFILL_BATCH)
+    .fetch_all(pool)
+    .await?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    let ids: Vec<i64> = rows.iter().map(|r| r.0).collect();
+    let documents: Vec<String> = rows
+        .iter()
+        .map(|r| search_document(r.1.as_deref().unwrap_or_default()))
+        .collect();
+    // an empty document still gives a non null vector, so every row is done once
+    sqlx::query(&format!(
+        r#"
+        UPDATE adv_chat.{table} t
+        SET search_vector = to_tsvector('simple', d.document)
+        FROM UNNEST($1::bigint[], $2::text[]) AS d(id, document)
+        WHERE t.{id} = d.id
+        "#,
+        id = id_column,
+        table = table,
+    ))
+    .bind(&ids)
+    .bind(&documents)
+    .execute(pool)
+    .await?;
+    Ok(rows.len())
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchConversation {
+    message_type: MessageType,
+    id: u64,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct SearchMessagesRequest {
+    session: Session,
+    query: String,
+    conversation: Option<SearchConversation>,
+    sender_id: Option<u64>,
+    since: Option<PrimitiveDateTime>,
+    until: Option<PrimitiveDateTime>,
+    offset: Option<u64>,
+    limit: Option<u64>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchHit {
+    message_type: MessageType,
+    message_id: u64,
+    sender_id: u64,
+    receiver_id: u64,
+    time: PrimitiveDateTime,
+    content: MessageContent,
+    preview: String,
+    highlights: Vec<(usize, usize)>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SearchMessagesResult {
+    state: OperationState,
+    hits: Option<Vec<SearchHit>>,
+}
+
+#[derive(Debug, FromRow)]
+struct SearchRow {
+    is_group: bool,
+    message_id: i64,
+    sender_id: i64,
+    receiver_id: i64,
+    content: SqlJson<MessageContent>,
+    preview: String,
+    time: PrimitiveDateTime,
+}
+
+pub async fn search_messages_client(
+    State(pool): State<ConnectionPool>,
+    State(session_map): State<SessionMap>,
+    Json(search_req): Json<SearchMessagesRequest>,
+) -> Json<SearchMessagesResult> {
+    let user_id = match get_user_id(session_map, search_req.session).await {
+        Some(user_id) => user_id,
+        None => {
+            return SearchMessagesResult {
+                state: OperationState::Err,
+                hits: None,
+            }
+            .into();
+        }
+    };
+    match search_messages(&pool, user_id as i64, &search_req).await {
+        Ok(hits) => SearchMessagesResult {
+            state: OperationState::Ok,
+            hits: Some(hits),
+        }
+        .into(),
+        Err(e) => {
+            debug!("failed to search messages: {:?}", e);
+            SearchMessagesResult {
+                state: OperationState::Err,
+                hits: None,
+            }
+            .into()
+        }
+    }
+}
+
+async fn search_messages(
+    pool: &ConnectionPool,
+    user_id: i64,
+    req: &SearchMessagesRequest,
+) -> Result<Vec<SearchHit>, sqlx::Error> {
+    let tokens = TOKENIZER.query_tokens(&req.query);
+    if tokens.is_empty() {
+        return Ok(vec![]);
+    }
+    let mut group_ids = get_user_group_ids(pool, user_id).await?;
+    let mut include_private = true;
+    let mut private_peer: Option<i64> = None;
+    match &req.conversation {
+        Some(SearchConversation {
+            message_type: MessageType::Private,
+            id,
+        }) => {
+            group_ids.clear();
+            private_peer = Some(*id as i64);
+        }
+        Some(SearchConversation {
+            message_type: MessageType::Group,
+            id,
+        }) => {
+            include_private = false;
+            group_ids.retain(|g_id| *g_id == *id as i64);
+        }
+        None => {}
+    }
+    let limit = req
+        .limit
+        .unwrap_or(DEFAULT_SEARCH_LIMIT)
+        .clamp(1, MAX_SEARCH_LIMIT);
+    let rows = sqlx::query_as::<_, SearchRow>(
+        r#"
+        SELECT * FROM (
+            SELECT
+            false as is_group,
+            message_id,
+            message_from as sender_id,
+            message_to as receiver_id,
+            COALESCE(content, jsonb_build_object('Text', jsonb_build_object('text', message))) as content,
+            message as preview,
+            created_at as time
+            FROM adv_chat.private_message
+            WHERE $1 AND (message_from = $2 OR message_to = $2)
+            AND ($3::bigint IS NULL
+                OR (message_from = $2 AND message_to = $3)
+                OR (message_from = $3 AND message_to = $2))
+            AND search_vector @@ plainto_tsquery('simple', $4)
+            AND (expires_at IS NULL OR expires_at > now() at time zone 'utc')
+            UNION ALL
+            SELECT
+            true as is_group,
+            group_message_id as message_id,
+            message_from as sender_id,
+            group_id as receiver_id,
+            COALESCE(content, jsonb_build_object('Text', jsonb_build_object('text', group_message))) as content,
+            group_message as preview,
+            created_at as time
+            FROM adv_chat.group_message m
+            WHERE group_id = ANY($5)
+            AND EXISTS (
+                SELECT 1 FROM adv_chat.group g
+                WHERE g.group_id = m.group_id AND $2 = ANY(g.user_list)
+                AND NOT $2 = ANY(COALESCE(g.banned_list, '{}'))
+            )
+            AND created_at >= COALESCE((
+                SELECT joined_at FROM adv_chat.group_member gm
+                WHERE gm.group_id = m.group_id AND gm.user_id = $2
+            ), '-infinity')
+            AND search_vector @@ plainto_tsquery('simple', $4)
+            AND (expires_at IS NULL OR expires_at > now() at time zone 'utc')
+        ) m
+        WHERE ($6::bigint IS NULL OR sender_id = $6)
+        AND ($7::timestamp IS NULL OR time >= $7)
+        AND ($8::timestamp IS NULL OR time < $8)
+        ORDER BY time DESC
+        LIMIT $9 OFFSET $10
+        "#,
+    )
+    .bind(include_private)
+    .bind(user_id)
+    .bind(private_peer)
+    .bind(tokens.join(" "))
+    .bind(&group_ids)
+    .bind(req.sender_id.map(|id| id as i64))
+    .bind(req.since)
+    .bind(req.until)
+    .bind(limit as i64)
+    .bind(req.offset.unwrap_or(0) as i64)
+    .fetch_all(pool)
+    .await?;
+    Ok(rows
+        .into_iter()
+        .map(|r| SearchHit {
+            message_type: if r.is_group {
+                MessageType::Group
+            } else {
+                MessageType::Private
+            },
+            message_id: r.message_id as u64,
+            sender_id: r.sender_id as u64,
+            receiver_id: r.receiver_id as u64,
+            time: r.time,
+            content: r.content.0,
+            highlights: highlight(&r.preview, &req.query),
+            preview: r.preview,
+        })
+        .collect())
+}
+
+#[test]
+fn test_ngram_tokenizer() {
+    assert_eq!(
+        TOKENIZER.index_tokens("Hello 你好世界!"),
+        vec!["hello", "你", "好", "世", "界", "你好", "好世", "世界"]
+    );
+    assert_eq!(
+        TOKENIZER.query_tokens("好世界 HELLO"),
+        vec!["好世", "世界", "hello"]
+    );
+    assert_eq!(TOKENIZER.query_tokens("猫"), vec!["猫"]);
+    assert!(TOKENIZER.query_tokens("?!").is_empty());
+}
+
+#[test]
+fn test_highlight() {
+    assert_eq!(highlight("今天天气很好", "天气"), vec![(2, 4)]);
+    assert_eq!(highlight("今天天气很好", "天气很"), vec![(2, 5)]);
+    assert_eq!(highlight("Rust rust", "RUST"), vec![(0, 4), (5, 9)]);
+    // only whole words are indexed, so only whole words match
+    assert!(highlight("rusty", "rust").is_empty());
+    assert!(highlight("abc", "xyz").is_empty());
+    // 'İ' lowercases to two chars, offsets still point into the original text
+    assert_eq!(highlight("İstanbul rust", "RUST"), vec![(9, 13)]);
+    assert_eq!(highlight("İstanbul", "İSTANBUL"), vec![(0, 8)]);
+}
+
+/// `DATABASE_URL=postgres://.. cargo test -- --ignored` against a database made by create_tables.sql
+#[tokio::test]
+#[ignore = "needs a local postgres"]
+async fn search_messages_finds_private_and_group_messages() {
+    let url = std::env::var("DATABASE_URL")
+        .unwrap_or_else(|_| "postgres://postgres@127.0.0.1/adv".to_string());
+    let pool = ConnectionPool::connect(&url).await.unwrap();
+    let mut users = vec![];
+    for _ in 0..2 {
+        let (user_id,): (i64,) = sqlx::query_as(
+            "INSERT INTO adv_chat.user (user_name, created_at)
+            VALUES('search', now() at time zone 'utc') RETURNING user_id",
+        )
+        .fetch_one(&pool)
+        .await
+        .unwrap();
+        users.push(user_id);
+    }
+    let (group_id,): (i64,) = sqlx::query_as(
+        "INSERT INTO adv_chat.group (group_name, group_host, user_list, created_at)
+        VALUES('search', $1, $2, now() at time zone 'utc') RETURNING group_id",
+    )
+    .bind(users[0])
+    .bind(&users)
+    .fetch_one(&pool)
+    .await
+    .unwrap();
+    sqlx::query("UPDATE adv_chat.user SET group_list = $1 WHERE user_id = ANY($2)")
+        .bind(vec![group_id])
+        .bind(&users)
+        .execute(&pool)
+        .await
+        .unwrap();
+    // a word no earlier run left behind, so only these messages can match
+    let word = format!("needle{}", users[0]);
+    sqlx::query(
+        "INSERT INTO adv_chat.private_message
+        (message_from, message_to, message, search_vector, created_at)
+        VALUES($1, $2, $3, to_tsvector('simple', $3), now() at time zone 'utc')",
+    )
+    .bind(users[0])
+    .bind(users[1])
+    .bind(format!("private {}", word))
+    .execute(&pool)
+    .await
+    .unwrap();
+    sqlx::query(
+        "INSERT INTO adv_chat.group_message
+        (message_from, group_id, group_message, search_vector, created_at)
+        VALUES($1, $2, $3, to_tsvector('simple', $3), now() at time zone 'utc')",
+    )
+    .bind(users[1])
+    .bind(group_id)
+    .bind(format!("group {}", word))
+    .execute(&pool)
+    .await
+    .unwrap();
+    let request = |conversation| SearchMessagesRequest {
+        session: Session {
+            session_id: uuid::Uuid::nil(),
+        },
+        query: word.clone(),
+        conversation,
+        sender_id: None,
+        since: None,
+        until: None,
+        offset: None,
+        limit: None,
+    };
+    let hits = search_messages(&pool, users[0], &request(None))
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 2);
+    let private = SearchConversation {
+        message_type: MessageType::Private,
+        id: users[1] as u64,
+    };
+    let hits = search_messages(&pool, users[0], &request(Some(private)))
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].preview, format!("private {}", word));
+    let group = SearchConversation {
+        message_type: MessageType::Group,
+        id: group_id as u64,
+    };
+    let hits = search_messages(&pool, users[1], &request(Some(group)))
+        .await
+        .unwrap();
+    assert_eq!(hits.len(), 1);
+    assert_eq!(hits[0].preview, format!("group {}", word));
+}
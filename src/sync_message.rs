@@ -1,13 +1,11 @@
 use axum::{extract::State, Json};
 use serde::{Deserialize, Serialize};
-use sqlx::FromRow;
-use time::{OffsetDateTime, UtcOffset, PrimitiveDateTime};
+use time::PrimitiveDateTime;
 use tracing::debug;
 
 use crate::{
-    helper::{get_user_id, ConnectionPool, MessageSender, OperationState, Session, SessionMap},
-    message::{ChatMessage, ChatMessageStored},
-    user_info::{get_user_group_ids, query_user_groups, ThisUserRequest},
+    helper::{get_user_id, ConnectionPool, OperationState, Session, SessionMap},
+    message::{ChatMessage, ChatMessageStored, MessageType},
 };
 
 #[derive(Debug, Serialize)]
@@ -19,7 +17,6 @@ pub struct SyncMessagesResult {
 #[derive(Debug, Deserialize)]
 pub struct SyncMessagesRequest {
     session: Session,
-    days: u64,
 }
 
 pub async fn sync_message_client(
@@ -28,7 +25,7 @@ pub async fn sync_message_client(
     Json(sync_messages_req): Json<SyncMessagesRequest>,
 ) -> Json<SyncMessagesResult> {
     let session = sync_messages_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return SyncMessagesResult {
             state: OperationState::Err,
@@ -37,7 +34,17 @@ pub async fn sync_message_client(
         .into();
     }
     let user_id = user_id.unwrap();
-    let messages = read_messages(&pool, user_id as i64).await.unwrap();
+    let messages = match read_messages(&pool, user_id as i64).await {
+        Ok(m) => m,
+        Err(e) => {
+            debug!("failed to read messages: {:?}", e);
+            return SyncMessagesResult {
+                state: OperationState::Err,
+                messages: None,
+            }
+            .into();
+        }
+    };
     SyncMessagesResult {
         state: OperationState::Ok,
         messages: Some(messages),
@@ -49,59 +56,54 @@ async fn read_messages(
     pool: &ConnectionPool,
     user_id: i64,
 ) -> Result<Vec<ChatMessage>, sqlx::Error> {
-    let mut private_messages = sqlx::query_as::<_, ChatMessageStored>(
+    let private_messages = sqlx::query_as::<_, ChatMessageStored>(
         r#"
         SELECT 
-        message as content,
+        message_id,
+        COALESCE(content, jsonb_build_object('Text', jsonb_build_object('text', message))) as content,
         message_from as sender_id,
         message_to as receiver_id,
-        created_at as time
+        created_at as time,
+        expires_at
         FROM adv_chat.private_message
-        WHERE message_from = $1 OR message_to = $1
+        WHERE (message_from = $1 OR message_to = $1)
+        AND (expires_at IS NULL OR expires_at > now() at time zone 'utc')
     "#,
     )
     .bind(user_id)
     .fetch_all(pool)
     .await?;
     let mut private_messages: Vec<ChatMessage> = private_messages
-        .iter()
-        .map(|m| ChatMessage {
-            content: m.content.clone(),
-            message_type: crate::message::MessageType::Private,
-            sender_id: m.sender_id as u64,
-            receiver_id: m.receiver_id as u64,
-            time: m.time,
-        })
+        .into_iter()
+        .map(|m| m.into_message(MessageType::Private))
         .collect();
 
-    let group_ids = get_user_group_ids(pool, user_id).await.unwrap();
-    let mut group_msgs: Vec<ChatMessageStored> = vec![];
-    for g_id in group_ids {
-        let mut g_messages = sqlx::query_as::<_, ChatMessageStored>(
-            r#"
-                SELECT 
-                group_message as content,
-                message_from as sender_id,
-                group_id as receiver_id,
-                created_at as time
-                FROM adv_chat.group_message
-                WHERE group_id = $1
-                "#,
-        )
-        .bind(g_id)
-        .fetch_all(pool)
-        .await?;
-        group_msgs.append(&mut g_messages);
-    }
+    // only groups whose user_list has the user and that didn't ban them, and
+    // only messages since they joined
+    let group_msgs = sqlx::query_as::<_, ChatMessageStored>(
+        r#"
+        SELECT 
+        m.group_message_id as message_id,
+        COALESCE(m.content, jsonb_build_object('Text', jsonb_build_object('text', m.group_message))) as content,
+        m.message_from as sender_id,
+        m.group_id as receiver_id,
+        m.created_at as time,
+        m.expires_at
+        FROM adv_chat.group_message m
+        JOIN adv_chat.group g ON g.group_id = m.group_id
+        LEFT JOIN adv_chat.group_member gm ON gm.group_id = m.group_id AND gm.user_id = $1
+        WHERE $1 = ANY(g.user_list)
+        AND NOT $1 = ANY(COALESCE(g.banned_list, '{}'))
+        AND (gm.joined_at IS NULL OR m.created_at >= gm.joined_at)
+        AND (m.expires_at IS NULL OR m.expires_at > now() at time zone 'utc')
+    "#,
+    )
+    .bind(user_id)
+    .fetch_all(pool)
+    .await?;
     let mut group_msgs: Vec<ChatMessage> = group_msgs
-        .iter()
-        .map(|m| ChatMessage {
-            content: m.content.clone(),
-            message_type: crate::message::MessageType::Group,
-            sender_id: m.sender_id as u64,
-            receiver_id: m.receiver_id as u64,
-            time: m.time,
-        })
+        .into_iter()
+        .map(|m| m.into_message(MessageType::Group))
         .collect();
     group_msgs.append(&mut private_messages);
     group_msgs.sort_by(|a, b| PrimitiveDateTime::cmp(&a.time, &b.time));
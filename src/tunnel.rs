@@ -0,0 +1,747 @@
+use axum::{
+    extract::{
+        ws::{CloseFrame, Message, WebSocket, WebSocketUpgrade},
+        Query, State,
+    },
+    response::IntoResponse,
+};
+use std::{
+    env,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use futures::{
+    sink::SinkExt,
+    stream::{SplitStream, StreamExt},
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    sync::{mpsc, oneshot, watch},
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{
+    app_state::AppState,
+    codec::{Codec, TUNNEL_PROTOCOLS, TUNNEL_PROTOCOLS_DEFLATE},
+    event::{push_event, push_event_to, ServerEvent, Target},
+    friends::in_contact,
+    group_info::get_group_users,
+    helper::{ConnectionPool, GroupInfoTable, Session},
+    message::{
+        in_conversation, submit_message, ChatMessageInfoState, Conversation, MessageType,
+        OutgoingMessage,
+    },
+    message_content::MessageContent,
+    outbound::{OutboundQueue, QueuePolicy},
+    presence::presence_changed,
+    replay::resume,
+    typing::{relay_typing, TypingLimiter},
+};
+
+pub type ConnectionId = u64;
+
+const MAX_BATCH: usize = 64;
+/// how long shutdown waits for tunnels to write what they have queued
+const CLOSE_GRACE: Duration = Duration::from_secs(10);
+
+/// ids are shared by tunnels and the sse and long-poll fallbacks. they are
+/// random so ids from different nodes don't collide, and fit in a javascript number
+pub fn next_connection_id() -> ConnectionId {
+    rand::random::<u64>() >> 11
+}
+
+/// one open tunnel or fallback stream, a user has one per device
+#[derive(Debug)]
+pub struct Connection {
+    pub sender: Arc<OutboundQueue>,
+    pub session: Session,
+    /// free form name the client passes as `?device=`
+    pub device: Option<String>,
+}
+
+/// the first frame of a tunnel
+#[derive(Debug, Deserialize)]
+pub struct Hello {
+    #[serde(flatten)]
+    session: Session,
+    /// the last `seq` received before reconnecting
+    last_seq: Option<u64>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TunnelParams {
+    device: Option<String>,
+}
+
+/// frames a client sends through the tunnel after the session frame
+#[derive(Debug, Deserialize)]
+pub enum ClientFrame {
+    Send {
+        /// chosen by the client, echoed back in the ack or error
+        client_id: u64,
+        message_type: MessageType,
+        receiver_id: u64,
+        content: MessageContent,
+        ttl_seconds: Option<u32>,
+    },
+    Typing {
+        message_type: MessageType,
+        /// the group id, or the other participant of a private chat
+        conversation_id: u64,
+        /// false to clear the indicator early
+        #[serde(default = "started_typing")]
+        typing: bool,
+    },
+    Read {
+        message_type: MessageType,
+        conversation_id: u64,
+        message_id: u64,
+    },
+    Ping {
+        nonce: u64,
+    },
+}
+
+fn started_typing() -> bool {
+    true
+}
+
+/// frames the server sends through the tunnel
+#[derive(Debug, Serialize, Clone)]
+pub enum ServerFrame {
+    /// `seq` is set for durable events, clients resume from the last one they saw
+    Event {
+        seq: Option<u64>,
+        event: ServerEvent,
+    },
+    /// sent once after the session frame, following any replayed events
+    Welcome {
+        connection_id: ConnectionId,
+        last_seq: u64,
+    },
+    /// missed events can't be replayed, the client has to sync its history again
+    ResyncRequired,
+    Ack {
+        client_id: u64,
+        message_id: u64,
+    },
+    Error {
+        client_id: Option<u64>,
+        reason: TunnelError,
+    },
+    Pong {
+        nonce: u64,
+    },
+    /// frames queued close together, in order
+    Batch(Vec<ServerFrame>),
+}
+
+#[derive(Debug, Serialize, Clone, Copy)]
+pub enum TunnelError {
+    InvalidFrame,
+    Send(ChatMessageInfoState),
+    NotGroupMember,
+    /// a private chat with someone who is neither a friend nor wrote before
+    NotInContact,
+    /// a read receipt for a message not in the conversation
+    MessageNotFound,
+    OtherError,
+}
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct ReadEvent {
+    message_type: MessageType,
+    conversation_id: u64,
+    message_id: u64,
+    user_id: u64,
+}
+
+pub async fn ws_handler(
+    ws: WebSocketUpgrade,
+    Query(params): Query<TunnelParams>,
+    State(state): State<AppState>,
+) -> impl IntoResponse {
+    debug!("{:?}", ws);
+    let ws = if state.tunnel_config.compression {
+        ws.protocols(TUNNEL_PROTOCOLS_DEFLATE)
+    } else {
+        ws.protocols(TUNNEL_PROTOCOLS)
+    };
+    ws.on_upgrade(move |socket| handle_socket(socket, state, params))
+}
+
+/// per deployment settings, read from `TUNNEL_PING_INTERVAL`, `TUNNEL_IDLE_TIMEOUT` (seconds),
+/// `TUNNEL_BATCH_WINDOW_MS` and `TUNNEL_COMPRESSION`
+#[derive(Debug, Clone, Copy)]
+pub struct TunnelConfig {
+    pub ping_interval: Duration,
+    /// a connection that sends nothing, pongs included, for this long is closed
+    pub idle_timeout: Duration,
+    /// frames queued within this window after the first go out as one batch, zero turns it off
+    pub batch_window: Duration,
+    /// whether the `+deflate` subprotocols are offered. they are not
+    /// permessage-deflate, so only clients written for them can use them
+    /// and they stay off unless turned on
+    pub compression: bool,
+    /// frames a connection may have waiting, `TUNNEL_QUEUE_CAPACITY`
+    pub queue_capacity: usize,
+    /// `TUNNEL_QUEUE_POLICY`, `drop` or `disconnect`
+    pub queue_policy: QueuePolicy,
+}
+
+impl Default for TunnelConfig {
+    fn default() -> Self {
+        TunnelConfig {
+            ping_interval: Duration::from_secs(30),
+            idle_timeout: Duration::from_secs(90),
+            batch_window: Duration::ZERO,
+            compression: false,
+            queue_capacity: 1024,
+            queue_policy: QueuePolicy::DropOldest,
+        }
+    }
+}
+
+impl TunnelConfig {
+    pub fn from_env() -> Self {
+        let default = TunnelConfig::default();
+        let seconds = |name: &str, default: Duration| {
+            env::var(name)
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .filter(|v| *v > 0)
+                .map(Duration::from_secs)
+                .unwrap_or(default)
+        };
+        TunnelConfig {
+            ping_interval: seconds("TUNNEL_PING_INTERVAL", default.ping_interval),
+            idle_timeout: seconds("TUNNEL_IDLE_TIMEOUT", default.idle_timeout),
+            batch_window: env::var("TUNNEL_BATCH_WINDOW_MS")
+                .ok()
+                .and_then(|v| v.parse::<u64>().ok())
+                .map(Duration::from_millis)
+                .unwrap_or(default.batch_window),
+            compression: env::var("TUNNEL_COMPRESSION")
+                .map(|v| matches!(v.as_str(), "1" | "true" | "on"))
+                .unwrap_or(default.compression),
+            queue_capacity: env::var("TUNNEL_QUEUE_CAPACITY")
+                .ok()
+                .and_then(|v| v.parse::<usize>().ok())
+                .filter(|v| *v > 0)
+                .unwrap_or(default.queue_capacity),
+            queue_policy: match env::var("TUNNEL_QUEUE_POLICY").as_deref() {
+                Ok("disconnect") => QueuePolicy::Disconnect,
+                Ok("drop") => QueuePolicy::DropOldest,
+                _ => default.queue_policy,
+            },
+        }
+    }
+}
+
+/// close codes in the 4000-4999 range websocket leaves to applications
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseReason {
+    Unauthorized = 4001,
+    IdleTimeout = 4002,
+    /// the client fell too far behind, it should resume or resync
+    SlowConsumer = 4003,
+    /// the server is shutting down, everything queued was sent first
+    ShuttingDown = 1001,
+}
+
+impl CloseReason {
+    fn frame(self) -> Message {
+        let reason = match self {
+            CloseReason::Unauthorized => "unauthorized",
+            CloseReason::IdleTimeout => "idle timeout",
+            CloseReason::SlowConsumer => "resync",
+            CloseReason::ShuttingDown => "going away",
+        };
+        Message::Close(Some(CloseFrame {
+            code: self as u16,
+            reason: reason.into(),
+        }))
+    }
+}
+
+/// lets a tunnel or sse stream know the server is shutting down, and tells
+/// the server while it is still open
+#[derive(Debug, Clone)]
+pub struct TunnelShutdown {
+    closing: watch::Receiver<bool>,
+    open: mpsc::WeakSender<()>,
+}
+
+impl TunnelShutdown {
+    /// changes once the stream should write what it has queued and end
+    pub fn closing(&self) -> watch::Receiver<bool> {
+        self.closing.clone()
+    }
+
+    /// held while the stream is open, `None` once the server is closing
+    pub fn hold_open(&self) -> Option<mpsc::Sender<()>> {
+        self.open.upgrade()
+    }
+}
+
+/// held by `main`, closes every tunnel and sse stream once the dispatcher has
+/// handed out the last messages
+pub struct TunnelCloser {
+    closing: watch::Sender<bool>,
+    open: mpsc::Sender<()>,
+    closed: mpsc::Receiver<()>,
+}
+
+impl TunnelCloser {
+    pub fn new() -> Self {
+        let (closing, _) = watch::channel(false);
+        let (open, closed) = mpsc::channel(1);
+        TunnelCloser {
+            closing,
+            open,
+            closed,
+        }
+    }
+
+    pub fn handle(&self) -> TunnelShutdown {
+        TunnelShutdown {
+            closing: self.closing.subscribe(),
+            open: self.open.downgrade(),
+        }
+    }
+
+    /// tells every tunnel to write what it has queued and close, and waits
+    /// up to `CLOSE_GRACE` for them
+    pub async fn close(self) {
+        let _ = self.closing.send(true);
+        drop(self.open);
+        let mut closed = self.closed;
+        if timeout(CLOSE_GRACE, closed.recv()).await.is_err() {
+            debug!("tunnels still open after {:?}", CLOSE_GRACE);
+        }
+    }
+}
+
+async fn handle_socket(socket: WebSocket, state: AppState, params: TunnelParams) {
+    let config = state.tunnel_config;
+    // held until the tunnel is done, `TunnelCloser::close` waits for it
+    let _open = match state.tunnel_shutdown.open.upgrade() {
+        Some(open) => open,
+        None => return,
+    };
+    let mut closing = state.tunnel_shutdown.closing.clone();
+    let codec = Codec::from_protocol(socket.protocol());
+    let (mut sender, mut receiver) = socket.split();
+    let hello = match timeout(config.idle_timeout, check_token(&mut receiver, codec)).await {
+        Ok(Ok(hello)) => hello,
+        _ => {
+            let _ = sender.send(CloseReason::Unauthorized.frame()).await;
+            return;
+        }
+    };
+    debug!("{:?}", hello);
+    let session = hello.session;
+    let user_id = state.sesson_map.user_id(session).await;
+    let user_id = match user_id {
+        Some(user_id) => user_id,
+        None => {
+            let _ = sender.send(CloseReason::Unauthorized.frame()).await;
+            return;
+        }
+    };
+    let queue = OutboundQueue::new(config.queue_capacity, config.queue_policy);
+    let (close_sender, mut close_receiver) = oneshot::channel::<CloseReason>();
+    let user_connection_map = state.user_connection_map.clone();
+    let connection_id = next_connection_id();
+    let connection = Connection {
+        sender: queue.clone(),
+        session,
+        device: params.device,
+    };
+    let came_online = resume(
+        &state.db_pool,
+        &user_connection_map,
+        user_id,
+        connection_id,
+        connection,
+        hello.last_seq,
+    )
+    .await;
+    if came_online {
+        presence_changed(&state.db_pool, &user_connection_map, user_id).await;
+    }
+
+    let outbound = queue.clone();
+    let mut send_task = tokio::spawn(async move {
+        let mut ping = tokio::time::interval(config.ping_interval);
+        ping.tick().await;
+        loop {
+            let message = tokio::select! {
+                frame = outbound.recv() => match frame {
+                    Some(frame) => {
+                        let frame = collect_batch(frame, &outbound, config.batch_window).await;
+                        codec.encode(&frame)
+                    }
+                    None => {
+                        debug!("tunnel of user {} fell behind, closing", user_id);
+                        let _ = sender.send(CloseReason::SlowConsumer.frame()).await;
+                        break;
+                    }
+                },
+                _ = ping.tick() => Message::Ping(vec![]),
+                reason = &mut close_receiver => {
+                    if let Ok(reason) = reason {
+                        let _ = sender.send(reason.frame()).await;
+                    }
+                    break;
+                }
+                _ = closing.changed() => {
+                    for frame in outbound.drain() {
+                        if sender.send(codec.encode(&frame)).await.is_err() {
+                            break;
+                        }
+                    }
+                    let _ = sender.send(CloseReason::ShuttingDown.frame()).await;
+                    break;
+                }
+            };
+            if sender.send(message).await.is_err() {
+                break;
+            }
+        }
+    });
+    let state_pool = state.db_pool.clone();
+    let mut recv_task = tokio::spawn(async move {
+        let mut typing_limiter = TypingLimiter::default();
+        loop {
+            let message = match timeout(config.idle_timeout, receiver.next()).await {
+                Ok(Some(Ok(message))) => message,
+                Ok(_) => break,
+                Err(_) => {
+                    debug!("tunnel of user {} idle, closing", user_id);
+                    let _ = close_sender.send(CloseReason::IdleTimeout);
+                    break;
+                }
+            };
+            if let Message::Close(_) = message {
+                break;
+            }
+            let frame = match codec.decode::<ClientFrame>(&message) {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if let Ok(ClientFrame::Typing {
+                message_type,
+                conversation_id,
+                typing,
+            }) = &frame
+            {
+                if !typing_limiter.allow(*message_type, *conversation_id, *typing, Instant::now()) {
+                    continue;
+                }
+            }
+            match frame {
+                Ok(frame) => handle_frame(&state, &queue, user_id, connection_id, frame).await,
+                Err(e) => {
+                    debug!("invalid tunnel frame: {}", e);
+                    reply(
+                        &queue,
+                        ServerFrame::Error {
+                            client_id: None,
+                            reason: TunnelError::InvalidFrame,
+                        },
+                    );
+                }
+            }
+        }
+    });
+    tokio::select! {
+        _ = &mut send_task => recv_task.abort(),
+        // the send task gets to write the close frame, then stops once the close channel is gone
+        _ = &mut recv_task => {
+            let _ = send_task.await;
+        }
+    }
+    let went_offline = user_connection_map
+        .lock()
+        .unwrap()
+        .remove(user_id, connection_id);
+    if went_offline {
+        presence_changed(&state_pool, &user_connection_map, user_id).await;
+    }
+}
+
+/// waits up to `window` for more frames to send along with `first`
+async fn collect_batch(first: ServerFrame, queue: &OutboundQueue, window: Duration) -> ServerFrame {
+    if window.is_zero() {
+        return first;
+    }
+    let deadline = tokio::time::sleep(window);
+    tokio::pin!(deadline);
+    let mut frames = vec![first];
+    while frames.len() < MAX_BATCH {
+        tokio::select! {
+            frame = queue.recv() => match frame {
+                Some(frame) => frames.push(frame),
+                None => break,
+            },
+            _ = &mut deadline => break,
+        }
+    }
+    if frames.len() == 1 {
+        frames.pop().unwrap()
+    } else {
+        ServerFrame::Batch(frames)
+    }
+}
+
+async fn check_token(stream: &mut SplitStream<WebSocket>, codec: Codec) -> Result<Hello, ()> {
+    match stream.next().await {
+        Some(Ok(message)) => match codec.decode(&message) {
+            Some(hello) => hello.map_err(|e| debug!("{:?}", e)),
+            None => Err(()),
+        },
+        _ => Err(()),
+    }
+}
+
+fn reply(connection: &OutboundQueue, frame: ServerFrame) {
+    if !connection.push(frame) {
+        debug!("outbound queue overflowed");
+    }
+}
+
+async fn handle_frame(
+    state: &AppState,
+    connection: &OutboundQueue,
+    user_id: u64,
+    connection_id: ConnectionId,
+    frame: ClientFrame,
+) {
+    match frame {
+        ClientFrame::Send {
+            client_id,
+            message_type,
+            receiver_id,
+            content,
+            ttl_seconds,
+        } => {
+            let outgoing = OutgoingMessage {
+                message_type,
+                receiver_id,
+                content,
+                ttl_seconds,
+            };
+            let result = submit_message(
+                &state.db_pool,
+                &state.group_info_table,
+                &state.message_sender,
+                user_id,
+                Some(connection_id),
+                outgoing,
+            )
+            .await;
+            let frame = match result {
+                Ok(message) => ServerFrame::Ack {
+                    client_id,
+                    message_id: message.message_id.unwrap_or_default(),
+                },
+                Err(state) => ServerFrame::Error {
+                    client_id: Some(client_id),
+                    reason: TunnelError::Send(state),
+                },
+            };
+            reply(connection, frame);
+        }
+        ClientFrame::Typing {
+            message_type,
+            conversation_id,
+            typing,
+        } => {
+            let result = relay_typing(
+                &state.db_pool,
+                &state.group_info_table,
+                &state.user_connection_map,
+                user_id,
+                message_type,
+                conversation_id,
+                typing,
+            )
+            .await;
+            if let Err(reason) = result {
+                reply(
+                    connection,
+                    ServerFrame::Error {
+                        client_id: None,
+                        reason,
+                    },
+                );
+            }
+        }
+        ClientFrame::Read {
+            message_type,
+            conversation_id,
+            message_id,
+        } => {
+            let result = mark_read(
+                state,
+                user_id,
+                connection_id,
+                message_type,
+                conversation_id,
+                message_id,
+            )
+            .await;
+            if let Err(reason) = result {
+                reply(
+                    connection,
+                    ServerFrame::Error {
+                        client_id: None,
+                        reason,
+                    },
+                );
+            }
+        }
+        ClientFrame::Ping { nonce } => reply(connection, ServerFrame::Pong { nonce }),
+    }
+}
+
+/// the other participants of a conversation the user takes part in, with the
+/// conversation id as each of them sees it. a private chat needs a peer the
+/// user is in contact with, a group needs the user as a member
+pub async fn other_participants(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    user_id: u64,
+    message_type: MessageType,
+    conversation_id: u64,
+) -> Result<Vec<(u64, u64)>, TunnelError> {
+    match message_type {
+        MessageType::Private => {
+            match in_contact(pool, user_id as i64, conversation_id as i64).await {
+                Ok(true) => Ok(vec![(conversation_id, user_id)]),
+                Ok(false) => Err(TunnelError::NotInContact),
+                Err(e) => {
+                    debug!("{:?}", e);
+                    Err(TunnelError::OtherError)
+                }
+            }
+        }
+        MessageType::Group => {
+            let users = get_group_users(group_info_table, pool, conversation_id as i64)
+                .await
+                .map_err(|e| {
+                    debug!("{:?}", e);
+                    TunnelError::OtherError
+                })?;
+            if !users.contains(&(user_id as i64)) {
+                return Err(TunnelError::NotGroupMember);
+            }
+            Ok(users
+                .into_iter()
+                .filter(|u| *u != user_id as i64)
+                .map(|u| (u as u64, conversation_id))
+                .collect())
+        }
+    }
+}
+
+async fn mark_read(
+    state: &AppState,
+    user_id: u64,
+    connection_id: ConnectionId,
+    message_type: MessageType,
+    conversation_id: u64,
+    message_id: u64,
+) -> Result<(), TunnelError> {
+    let pool = &state.db_pool;
+    let user_connection_map = &state.user_connection_map;
+    let recipients = other_participants(
+        pool,
+        &state.group_info_table,
+        user_id,
+        message_type,
+        conversation_id,
+    )
+    .await?;
+    let conversation = match message_type {
+        MessageType::Private => Conversation::private(user_id as i64, conversation_id as i64),
+        MessageType::Group => Conversation::Group(conversation_id as i64),
+    };
+    match in_conversation(pool, conversation, message_id).await {
+        Ok(true) => {}
+        Ok(false) => return Err(TunnelError::MessageNotFound),
+        Err(e) => {
+            debug!("{:?}", e);
+            return Err(TunnelError::OtherError);
+        }
+    }
+    sqlx::query(
+        r#"
+        INSERT INTO adv_chat.read_marker
+        (user_id, conversation_key, message_id, updated_at)
+        VALUES($1, $2, $3, now() at time zone 'utc')
+        ON CONFLICT (user_id, conversation_key)
+        DO UPDATE SET message_id = GREATEST(adv_chat.read_marker.message_id, $3),
+        updated_at = now() at time zone 'utc'
+        "#,
+    )
+    .bind(user_id as i64)
+    .bind(conversation.key())
+    .bind(message_id as i64)
+    .execute(pool)
+    .await
+    .map_err(|e| {
+        debug!("failed to store read marker: {:?}", e);
+        TunnelError::OtherError
+    })?;
+    for (recipient, conversation_id) in recipients {
+        let event = ServerEvent::Read(ReadEvent {
+            message_type,
+            conversation_id,
+            message_id,
+            user_id,
+        });
+        push_event(user_connection_map, recipient, &event);
+    }
+    // the reader's other devices clear their unread state too
+    let event = ServerEvent::Read(ReadEvent {
+        message_type,
+        conversation_id,
+        message_id,
+        user_id,
+    });
+    push_event_to(
+        user_connection_map,
+        user_id,
+        Target::Except(connection_id),
+        &event,
+    );
+    Ok(())
+}
+
+#[test]
+fn parse_client_frames() {
+    let frame: ClientFrame = serde_json::from_str(
+        r#"{"Send":{"client_id":7,"message_type":"Private","receiver_id":100001,"content":{"Text":{"text":"hi"}},"ttl_seconds":null}}"#,
+    )
+    .unwrap();
+    assert!(matches!(frame, ClientFrame::Send { client_id: 7, .. }));
+    let frame: ClientFrame = serde_json::from_str(r#"{"Ping":{"nonce":1}}"#).unwrap();
+    assert!(matches!(frame, ClientFrame::Ping { nonce: 1 }));
+    assert!(serde_json::from_str::<ClientFrame>(r#"{"Unknown":{}}"#).is_err());
+}
+
+#[test]
+fn parse_hello() {
+    let hello: Hello =
+        serde_json::from_str(r#"{"session_id":"67e55044-10b1-426f-9247-bb680e5fe0c8"}"#).unwrap();
+    assert!(hello.last_seq.is_none());
+    let hello: Hello = serde_json::from_str(
+        r#"{"session_id":"67e55044-10b1-426f-9247-bb680e5fe0c8","last_seq":42}"#,
+    )
+    .unwrap();
+    assert_eq!(hello.last_seq, Some(42));
+}
@@ -0,0 +1,115 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    event::{push_event, ServerEvent},
+    helper::{ConnectionPool, GroupInfoTable, UserConnectionMap},
+    message::MessageType,
+    tunnel::{other_participants, TunnelError},
+};
+
+/// clients drop an indicator that isn't renewed within this time
+const TYPING_EXPIRES: Duration = Duration::from_secs(6);
+/// a connection may renew an indicator at most this often
+const MIN_TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct TypingEvent {
+    message_type: MessageType,
+    /// the group id, or the other participant of a private chat
+    conversation_id: u64,
+    user_id: u64,
+    /// false once the user stopped or sent the message
+    typing: bool,
+    expires_in_ms: u64,
+}
+
+/// drops typing signals a connection repeats too quickly for a conversation
+#[derive(Debug, Default)]
+pub struct TypingLimiter {
+    last: HashMap<(MessageType, u64), Instant>,
+}
+
+impl TypingLimiter {
+    pub fn allow(
+        &mut self,
+        message_type: MessageType,
+        conversation_id: u64,
+        typing: bool,
+        now: Instant,
+    ) -> bool {
+        self.last
+            .retain(|_, at| now.duration_since(*at) < TYPING_EXPIRES);
+        let key = (message_type, conversation_id);
+        if !typing {
+            // nothing to stop if the start never went out or has expired anyway
+            return self.last.remove(&key).is_some();
+        }
+        match self.last.get(&key) {
+            Some(at) if now.duration_since(*at) < MIN_TYPING_INTERVAL => false,
+            _ => {
+                self.last.insert(key, now);
+                true
+            }
+        }
+    }
+}
+
+/// pushes the signal to the other participants, only those connected receive it
+/// and nothing is stored
+pub async fn relay_typing(
+    pool: &ConnectionPool,
+    group_info_table: &GroupInfoTable,
+    user_connection_map: &UserConnectionMap,
+    user_id: u64,
+    message_type: MessageType,
+    conversation_id: u64,
+    typing: bool,
+) -> Result<(), TunnelError> {
+    for (recipient, conversation_id) in other_participants(
+        pool,
+        group_info_table,
+        user_id,
+        message_type,
+        conversation_id,
+    )
+    .await?
+    {
+        let event = ServerEvent::Typing(TypingEvent {
+            message_type,
+            conversation_id,
+            user_id,
+            typing,
+            expires_in_ms: TYPING_EXPIRES.as_millis() as u64,
+        });
+        push_event(user_connection_map, recipient, &event);
+    }
+    Ok(())
+}
+
+#[test]
+fn typing_limiter() {
+    let mut limiter = TypingLimiter::default();
+    let start = Instant::now();
+    assert!(limiter.allow(MessageType::Group, 1, true, start));
+    assert!(!limiter.allow(MessageType::Group, 1, true, start + Duration::from_secs(1)));
+    assert!(limiter.allow(
+        MessageType::Private,
+        1,
+        true,
+        start + Duration::from_secs(1)
+    ));
+    assert!(limiter.allow(MessageType::Group, 1, true, start + MIN_TYPING_INTERVAL));
+    assert!(limiter.allow(MessageType::Group, 1, false, start + MIN_TYPING_INTERVAL));
+    assert!(!limiter.allow(MessageType::Group, 1, false, start + MIN_TYPING_INTERVAL));
+    assert!(!limiter.allow(
+        MessageType::Private,
+        1,
+        false,
+        start + TYPING_EXPIRES + Duration::from_secs(1)
+    ));
+}
@@ -4,8 +4,13 @@ use sqlx::FromRow;
 use tracing::debug;
 
 use crate::{
-    group_info::{get_group, group_add_user, Group},
-    helper::{get_user_id, ConnectionPool, OperationState, Session, SessionMap},
+    group_info::{get_group, get_member_status, group_add_user, Group, MemberStatus},
+    helper::{
+        get_user_id, ConnectionPool, GroupInfoTable, MessageSender, OperationState, Session,
+        SessionMap,
+    },
+    message::send_system_message,
+    message_content::SystemNotice,
 };
 
 #[derive(Debug, Serialize)]
@@ -66,7 +71,7 @@ pub async fn query_user_info(
     UserInfoResult {
         state: UserInfoQueryState::Ok,
         info: Some(UserInfo {
-            user_id: user_id,
+            user_id,
             user_name,
             avatar,
         }),
@@ -80,7 +85,7 @@ pub async fn query_user_this(
     Json(user_info_req): Json<ThisUserRequest>,
 ) -> Json<UserInfoResult> {
     let session = user_info_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return UserInfoResult {
             state: UserInfoQueryState::Error,
@@ -113,7 +118,7 @@ pub async fn query_user_this(
     UserInfoResult {
         state: UserInfoQueryState::Ok,
         info: Some(UserInfo {
-            user_id: user_id,
+            user_id,
             user_name,
             avatar,
         }),
@@ -138,7 +143,7 @@ pub async fn query_user_groups(
     Json(user_groups_req): Json<UserGroupsRequest>,
 ) -> Json<UserGroupsResult> {
     let session = user_groups_req.session;
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return UserGroupsResult {
             state: UserInfoQueryState::Error,
@@ -160,9 +165,7 @@ pub async fn query_user_groups(
     };
     let mut groups = vec![];
     for g_id in group_ids {
-        let g = get_group(&pool, g_id).await;
-        if g.is_ok() {
-            let g = g.unwrap();
+        if let Ok(g) = get_group(&pool, g_id).await {
             groups.push(g);
         }
     }
@@ -173,7 +176,10 @@ pub async fn query_user_groups(
     .into()
 }
 
-pub async fn get_user_group_ids(pool: &ConnectionPool, user_id: i64) -> Result<Vec<i64>, sqlx::Error> {
+pub async fn get_user_group_ids(
+    pool: &ConnectionPool,
+    user_id: i64,
+) -> Result<Vec<i64>, sqlx::Error> {
     let group_list = sqlx::query_as::<_, (i64,)>(
         r#"
     SELECT UNNEST(group_list)
@@ -188,6 +194,20 @@ pub async fn get_user_group_ids(pool: &ConnectionPool, user_id: i64) -> Result<V
     Ok(group_list)
 }
 
+pub async fn user_exists(pool: &ConnectionPool, user_id: i64) -> Result<bool, sqlx::Error> {
+    let row = sqlx::query_as::<_, (i64,)>(
+        r#"
+    SELECT user_id
+    FROM adv_chat.user
+    WHERE user_id = $1
+    "#,
+    )
+    .bind(user_id)
+    .fetch_optional(pool)
+    .await?;
+    Ok(row.is_some())
+}
+
 #[derive(Debug, Serialize)]
 pub struct GroupAddMemberResult {
     state: OperationState,
@@ -202,12 +222,14 @@ pub struct GroupAddMemberRequest {
 pub async fn group_add_member(
     State(pool): State<ConnectionPool>,
     State(session_map): State<SessionMap>,
+    State(message_sender): State<MessageSender>,
+    State(group_info_table): State<GroupInfoTable>,
     Json(group_add_member): Json<GroupAddMemberRequest>,
 ) -> Json<GroupAddMemberResult> {
     let session = group_add_member.session;
     let new_group_id = group_add_member.group_id as i64;
     debug!("{:?}", group_add_member);
-    let user_id = get_user_id(session_map, session);
+    let user_id = get_user_id(session_map, session).await;
     if user_id.is_none() {
         return GroupAddMemberResult {
             state: OperationState::Err,
@@ -215,7 +237,13 @@ pub async fn group_add_member(
         .into();
     }
     let user_id = user_id.unwrap();
-    match group_add_user(&pool, new_group_id, user_id as i64).await {
+    match get_member_status(&group_info_table, &pool, new_group_id, user_id as i64).await {
+        Ok(MemberStatus::Banned) => {
+            return GroupAddMemberResult {
+                state: OperationState::Err,
+            }
+            .into();
+        }
         Ok(_) => {}
         Err(e) => {
             debug!("{:?}", e);
@@ -224,6 +252,17 @@ pub async fn group_add_member(
             }
             .into();
         }
+    }
+    let joined = match group_add_user(&group_info_table, &pool, new_group_id, user_id as i64).await
+    {
+        Ok(joined) => joined,
+        Err(e) => {
+            debug!("{:?}", e);
+            return GroupAddMemberResult {
+                state: OperationState::Err,
+            }
+            .into();
+        }
     };
     let mut group_ids = match get_user_group_ids(&pool, user_id as i64).await {
         Ok(g_ids) => g_ids,
@@ -248,6 +287,19 @@ pub async fn group_add_member(
             }
         };
     }
+    if joined {
+        if let Err(e) = send_system_message(
+            &pool,
+            &message_sender,
+            new_group_id as u64,
+            user_id,
+            SystemNotice::MemberJoined { user_id },
+        )
+        .await
+        {
+            debug!("failed to record member join: {:?}", e);
+        }
+    }
     GroupAddMemberResult {
         state: OperationState::Ok,
     }
@@ -270,6 +322,5 @@ async fn set_user_group_ids(
     .bind(user_id)
     .execute(pool)
     .await?;
-
-    unimplemented!()
+    Ok(())
 }
@@ -10,29 +10,165 @@ CREATE TABLE adv_chat.user(
     created_at timestamp
 );
 
+-- login sessions, shared by every node. only a sha256 of the session id is kept
+CREATE TABLE adv_chat.session(
+    session_digest bytea primary key,
+    user_id bigint REFERENCES adv_chat.user,
+    created_at timestamp
+);
+
 CREATE TABLE adv_chat.group(
     group_id bigserial primary key,
     group_name varchar(64),
     group_host bigint REFERENCES adv_chat.user,
     admin_list bigint[],
     user_list bigint[],
+    muted_list bigint[],
+    banned_list bigint[],
     created_at timestamp
 );
 
+CREATE TABLE adv_chat.group_member(
+    group_id bigint REFERENCES adv_chat.group,
+    user_id bigint REFERENCES adv_chat.user,
+    joined_at timestamp,
+    primary key (group_id, user_id)
+);
+
 CREATE TABLE adv_chat.private_message(
     message_id bigserial primary key,
     message varchar(4096),
+    content jsonb,
+    search_vector tsvector,
     message_from bigint REFERENCES adv_chat.user,
     message_to bigint REFERENCES adv_chat.user,
-    created_at timestamp
+    created_at timestamp,
+    expires_at timestamp
 );
 CREATE TABLE adv_chat.group_message(
     group_message_id bigserial primary key,
     message_from bigint REFERENCES adv_chat.user,
     group_id bigint REFERENCES adv_chat.group,
     group_message varchar(4096),
+    content jsonb,
+    search_vector tsvector,
+    created_at timestamp,
+    expires_at timestamp
+);
+
+CREATE TABLE adv_chat.pinned_message(
+    pin_id bigserial primary key,
+    is_group boolean,
+    message_id bigint,
+    group_id bigint REFERENCES adv_chat.group,
+    user_a bigint REFERENCES adv_chat.user,
+    user_b bigint REFERENCES adv_chat.user,
+    pinned_by bigint REFERENCES adv_chat.user,
+    created_at timestamp,
+    UNIQUE (is_group, message_id)
+);
+
+CREATE TABLE adv_chat.scheduled_message(
+    scheduled_id bigserial primary key,
+    is_group boolean,
+    sender_id bigint REFERENCES adv_chat.user,
+    receiver_id bigint,
+    content jsonb,
+    send_at timestamp,
     created_at timestamp
 );
+CREATE INDEX scheduled_message_sender_idx ON adv_chat.scheduled_message (sender_id);
+
+-- conversation_key is "p:<smaller user id>:<larger user id>" or "g:<group id>"
+CREATE TABLE adv_chat.conversation_ttl(
+    conversation_key varchar(64) primary key,
+    ttl_seconds integer,
+    updated_by bigint REFERENCES adv_chat.user,
+    updated_at timestamp
+);
+CREATE TABLE adv_chat.read_marker(
+    user_id bigint REFERENCES adv_chat.user,
+    conversation_key varchar(64),
+    message_id bigint,
+    updated_at timestamp,
+    primary key (user_id, conversation_key)
+);
+
+CREATE TABLE adv_chat.presence(
+    user_id bigint primary key REFERENCES adv_chat.user,
+    status varchar(16) DEFAULT 'available',
+    last_seen timestamp
+);
+
+-- the node slots a user has connections on, a user is online while any row is left
+CREATE TABLE adv_chat.user_connection(
+    user_id bigint,
+    slot integer,
+    primary key (user_id, slot)
+);
+
+-- platform is 'fcm' or 'apns', see notifier.rs
+CREATE TABLE adv_chat.push_token(
+    token varchar(512) primary key,
+    user_id bigint REFERENCES adv_chat.user,
+    platform varchar(8),
+    created_at timestamp
+);
+CREATE INDEX push_token_user_idx ON adv_chat.push_token (user_id);
+
+-- quiet hours as minutes of the day in the user's local time, see push.rs
+CREATE TABLE adv_chat.push_setting(
+    user_id bigint primary key REFERENCES adv_chat.user,
+    quiet_start_minute integer,
+    quiet_end_minute integer,
+    utc_offset_minutes integer
+);
+
+-- durable tunnel events, replayed when a client resumes, see replay.rs.
+-- event is null where an event was lost before it was stored
+CREATE TABLE adv_chat.user_event(
+    user_id bigint,
+    seq bigint,
+    event jsonb,
+    created_at timestamp,
+    primary key (user_id, seq)
+);
+CREATE INDEX user_event_created_idx ON adv_chat.user_event (created_at);
+
+-- newest event number stored by each node slot, so numbers keep growing across restarts,
+-- and the newest number its pruning deleted, events after it can still be replayed
+CREATE TABLE adv_chat.event_node(
+    slot integer primary key,
+    last_seq bigint,
+    pruned_seq bigint
+);
+
+-- stored messages not yet pushed, written in the message's transaction, see outbox.rs
+CREATE TABLE adv_chat.message_outbox(
+    outbox_id bigserial primary key,
+    message jsonb,
+    origin bigint,
+    created_at timestamp,
+    claimed_at timestamp,
+    dispatched_at timestamp
+);
+CREATE INDEX message_outbox_pending_idx ON adv_chat.message_outbox (outbox_id) WHERE dispatched_at IS NULL;
+CREATE INDEX message_outbox_dispatched_idx ON adv_chat.message_outbox (dispatched_at);
+
+-- notifications too big for pg_notify, only used with EVENT_BUS=postgres, see pg_bus.rs
+CREATE TABLE adv_chat.cluster_event(
+    event_id bigserial primary key,
+    message jsonb,
+    created_at timestamp
+);
+
+CREATE INDEX private_message_pair_idx ON adv_chat.private_message (message_from, message_to);
+CREATE INDEX private_message_expires_idx ON adv_chat.private_message (expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX group_message_expires_idx ON adv_chat.group_message (expires_at) WHERE expires_at IS NOT NULL;
+
+-- search_vector holds the n-gram tokens of the message text, see search.rs
+CREATE INDEX private_message_search_idx ON adv_chat.private_message USING GIN (search_vector);
+CREATE INDEX group_message_search_idx ON adv_chat.group_message USING GIN (search_vector);
 
 ALTER SEQUENCE adv_chat.user_user_id_seq RESTART WITH 100000;
 ALTER SEQUENCE adv_chat.group_group_id_seq RESTART WITH 100000;
\ No newline at end of file
@@ -0,0 +1,131 @@
+-- brings a database made by the first create_tables.sql up to the current one,
+-- safe to run again. the server fills search_vector of the existing messages on
+-- startup, see backfill_search_index in search.rs. messages without content are
+-- read as text, groups without muted_list or banned_list as having nobody in them
+
+CREATE TABLE IF NOT EXISTS adv_chat.session(
+    session_digest bytea primary key,
+    user_id bigint REFERENCES adv_chat.user,
+    created_at timestamp
+);
+
+ALTER TABLE adv_chat.group ADD COLUMN IF NOT EXISTS muted_list bigint[];
+ALTER TABLE adv_chat.group ADD COLUMN IF NOT EXISTS banned_list bigint[];
+
+-- members from before this table count as members since the group was made
+CREATE TABLE IF NOT EXISTS adv_chat.group_member(
+    group_id bigint REFERENCES adv_chat.group,
+    user_id bigint REFERENCES adv_chat.user,
+    joined_at timestamp,
+    primary key (group_id, user_id)
+);
+
+ALTER TABLE adv_chat.private_message ADD COLUMN IF NOT EXISTS content jsonb;
+ALTER TABLE adv_chat.private_message ADD COLUMN IF NOT EXISTS search_vector tsvector;
+ALTER TABLE adv_chat.private_message ADD COLUMN IF NOT EXISTS expires_at timestamp;
+ALTER TABLE adv_chat.group_message ADD COLUMN IF NOT EXISTS content jsonb;
+ALTER TABLE adv_chat.group_message ADD COLUMN IF NOT EXISTS search_vector tsvector;
+ALTER TABLE adv_chat.group_message ADD COLUMN IF NOT EXISTS expires_at timestamp;
+
+CREATE TABLE IF NOT EXISTS adv_chat.pinned_message(
+    pin_id bigserial primary key,
+    is_group boolean,
+    message_id bigint,
+    group_id bigint REFERENCES adv_chat.group,
+    user_a bigint REFERENCES adv_chat.user,
+    user_b bigint REFERENCES adv_chat.user,
+    pinned_by bigint REFERENCES adv_chat.user,
+    created_at timestamp,
+    UNIQUE (is_group, message_id)
+);
+
+CREATE TABLE IF NOT EXISTS adv_chat.scheduled_message(
+    scheduled_id bigserial primary key,
+    is_group boolean,
+    sender_id bigint REFERENCES adv_chat.user,
+    receiver_id bigint,
+    content jsonb,
+    send_at timestamp,
+    created_at timestamp
+);
+CREATE INDEX IF NOT EXISTS scheduled_message_sender_idx ON adv_chat.scheduled_message (sender_id);
+
+CREATE TABLE IF NOT EXISTS adv_chat.conversation_ttl(
+    conversation_key varchar(64) primary key,
+    ttl_seconds integer,
+    updated_by bigint REFERENCES adv_chat.user,
+    updated_at timestamp
+);
+CREATE TABLE IF NOT EXISTS adv_chat.read_marker(
+    user_id bigint REFERENCES adv_chat.user,
+    conversation_key varchar(64),
+    message_id bigint,
+    updated_at timestamp,
+    primary key (user_id, conversation_key)
+);
+
+CREATE TABLE IF NOT EXISTS adv_chat.presence(
+    user_id bigint primary key REFERENCES adv_chat.user,
+    status varchar(16) DEFAULT 'available',
+    last_seen timestamp
+);
+
+CREATE TABLE IF NOT EXISTS adv_chat.user_connection(
+    user_id bigint,
+    slot integer,
+    primary key (user_id, slot)
+);
+
+CREATE TABLE IF NOT EXISTS adv_chat.push_token(
+    token varchar(512) primary key,
+    user_id bigint REFERENCES adv_chat.user,
+    platform varchar(8),
+    created_at timestamp
+);
+CREATE INDEX IF NOT EXISTS push_token_user_idx ON adv_chat.push_token (user_id);
+
+CREATE TABLE IF NOT EXISTS adv_chat.push_setting(
+    user_id bigint primary key REFERENCES adv_chat.user,
+    quiet_start_minute integer,
+    quiet_end_minute integer,
+    utc_offset_minutes integer
+);
+
+CREATE TABLE IF NOT EXISTS adv_chat.user_event(
+    user_id bigint,
+    seq bigint,
+    event jsonb,
+    created_at timestamp,
+    primary key (user_id, seq)
+);
+CREATE INDEX IF NOT EXISTS user_event_created_idx ON adv_chat.user_event (created_at);
+
+CREATE TABLE IF NOT EXISTS adv_chat.event_node(
+    slot integer primary key,
+    last_seq bigint,
+    pruned_seq bigint
+);
+ALTER TABLE adv_chat.event_node ADD COLUMN IF NOT EXISTS pruned_seq bigint;
+
+CREATE TABLE IF NOT EXISTS adv_chat.message_outbox(
+    outbox_id bigserial primary key,
+    message jsonb,
+    origin bigint,
+    created_at timestamp,
+    claimed_at timestamp,
+    dispatched_at timestamp
+);
+CREATE INDEX IF NOT EXISTS message_outbox_pending_idx ON adv_chat.message_outbox (outbox_id) WHERE dispatched_at IS NULL;
+CREATE INDEX IF NOT EXISTS message_outbox_dispatched_idx ON adv_chat.message_outbox (dispatched_at);
+
+CREATE TABLE IF NOT EXISTS adv_chat.cluster_event(
+    event_id bigserial primary key,
+    message jsonb,
+    created_at timestamp
+);
+
+CREATE INDEX IF NOT EXISTS private_message_pair_idx ON adv_chat.private_message (message_from, message_to);
+CREATE INDEX IF NOT EXISTS private_message_expires_idx ON adv_chat.private_message (expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS group_message_expires_idx ON adv_chat.group_message (expires_at) WHERE expires_at IS NOT NULL;
+CREATE INDEX IF NOT EXISTS private_message_search_idx ON adv_chat.private_message USING GIN (search_vector);
+CREATE INDEX IF NOT EXISTS group_message_search_idx ON adv_chat.group_message USING GIN (search_vector);